@@ -0,0 +1,31 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    // k210 上 RustSBI 占用了 0x80000000 开始的 128K，内核从 0x80020000 开始
+    let linker_script = if env::var_os("CARGO_FEATURE_K210").is_some() {
+        "src/linker-k210.ld"
+    } else {
+        "src/linker.ld"
+    };
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+    println!("cargo:rustc-link-arg=-T{}/{}", manifest_dir, linker_script);
+    println!("cargo:rerun-if-changed={}", linker_script);
+
+    // 内核符号表，由 Makefile 在第一次链接后从 ELF 中提取，没有时使用空表
+    let ksyms = match env::var("KSYMS") {
+        Ok(path) if Path::new(&path).exists() => path,
+        _ => {
+            let path = Path::new(&env::var("OUT_DIR").unwrap()).join("ksyms.txt");
+            if !path.exists() {
+                fs::write(&path, "").unwrap();
+            }
+            path.to_str().unwrap().into()
+        }
+    };
+    println!("cargo:rustc-env=KSYMS_PATH={}", ksyms);
+    println!("cargo:rerun-if-changed={}", ksyms);
+    println!("cargo:rerun-if-env-changed=KSYMS");
+    println!("cargo:rerun-if-changed=build.rs");
+}
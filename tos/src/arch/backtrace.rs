@@ -0,0 +1,38 @@
+//! 沿帧指针链回溯调用栈
+//!
+//! 需要保留帧指针（`-C force-frame-pointers=yes`），每个栈帧中
+//! `fp - 8` 处为返回地址，`fp - 16` 处为上一个栈帧的 fp
+use super::config::{KERNEL_STACK_AREA_START, KERNEL_STACK_TOP};
+use super::interface::Register;
+use super::machine::machine;
+use super::register::RegisterImpl;
+use crate::ksyms::Symbol;
+
+/// 最多打印的栈帧数
+const MAX_DEPTH: usize = 32;
+
+/// fp 是否位于启动栈、堆或内核线程栈中，避免回溯时访问未映射的地址
+fn is_valid_fp(fp: usize) -> bool {
+    let machine = machine();
+    let in_memory = fp > machine.memory_start && fp <= machine.memory_end;
+    let in_kernel_stack = fp > KERNEL_STACK_AREA_START && fp <= KERNEL_STACK_TOP;
+    fp % 8 == 0 && (in_memory || in_kernel_stack)
+}
+
+/// 打印当前调用栈中的返回地址及所在的函数
+#[inline(never)]
+pub fn backtrace() {
+    let mut fp = RegisterImpl::fp();
+    println!("stack backtrace:");
+    for depth in 0..MAX_DEPTH {
+        if !is_valid_fp(fp) {
+            break;
+        }
+        let (ra, prev_fp) = unsafe { (*((fp - 8) as *const usize), *((fp - 16) as *const usize)) };
+        if ra == 0 {
+            break;
+        }
+        println!("{:>4}: {}", depth, Symbol(ra));
+        fp = prev_fp;
+    }
+}
@@ -1,29 +1,86 @@
 #[allow(unused)]
-//TODO 适配k210
 /// 内核使用线性映射的偏移量
 pub const KERNEL_MAP_OFFSET: usize = 0xFFFF_FFC0_0000_0000;
-/// 用户栈大小
-pub const USER_STACK_SIZE: usize = 1 << 13;
+/// 用户地址空间的上界，Sv39 与 Sv48 的低半部分都包含 [0, USER_SPACE_END)
+pub const USER_SPACE_END: usize = 1 << 38;
 /// 每个内核栈的栈顶都为 1 << KERNEL_STACK_SIZE_BITS 的倍数
 pub const KERNEL_STACK_ALIGN_BITS: usize = 14;
-/// 内核栈大小，最大为 1 << KERNEL_STACK_SIZE_BITS - PAGE_SIZE
+/// 内核栈大小，最大为 1 << KERNEL_STACK_SIZE_BITS - PAGE_SIZE，
+/// 每个内核栈之下未映射的部分作为保护页，与 trap.asm 中的 KERNEL_STACK_SIZE_BITS 一致
 pub const KERNEL_STACK_SIZE: usize = 1 << 13;
-/// 内核堆大小
-pub const KERNEL_HEAP_SIZE: usize = 0x30_0000;
-/// 内存起始地址
+/// 内核线程栈都位于地址空间最高的 1 << KERNEL_STACK_AREA_BITS 中
+pub const KERNEL_STACK_AREA_BITS: usize = 30;
+pub const KERNEL_STACK_AREA_START: usize = usize::MAX - (1 << KERNEL_STACK_AREA_BITS) + 1;
+/// 每个核的紧急中断栈大小，内核栈溢出时使用，与 trap.asm 中的 EMERGENCY_STACK_BITS 一致
+pub const EMERGENCY_STACK_SIZE: usize = 1 << 14;
+/// 内存起始地址，设备树不可用时使用
 pub const MEMORY_START: usize = 0xFFFF_FFC0_8000_0000;
-/// 内存大小
+/// 内存大小，设备树不可用时使用，实际大小见 `machine::machine()`
+#[cfg(not(feature = "k210"))]
 pub const MEMORY_SIZE: usize = 0x80_0000;
+/// k210 的 8M SRAM 中，最后 2M 为 KPU 使用的 AI SRAM
+#[cfg(feature = "k210")]
+pub const MEMORY_SIZE: usize = 0x60_0000;
 
 pub const MEMORY_END: usize = MEMORY_START + MEMORY_SIZE;
 /// PAGE_SIZE = 1 << PAGE_SIZE_BITS
 pub const PAGE_SIZE_BITS: usize = 12;
-/// MMIO 起始地址
-pub const MMIO: [(usize, usize); 1] = [(0x10001000, 0x1000)];
+/// MMIO 起始地址，设备树不可用时使用
+#[cfg(not(feature = "k210"))]
+pub const MMIO: &[(usize, usize)] = &[
+    (0x0010_1000, 0x1000),  // Goldfish RTC
+    (0x0C00_0000, 0x21_0000),  // PLIC
+    (0x1000_0000, 0x1000),  // UART0
+    (0x1000_1000, 0x1000),  // VIRTIO0
+];
+#[cfg(feature = "k210")]
+pub const MMIO: &[(usize, usize)] = &[
+    (0x0C00_0000, 0x3000),  // PLIC
+    (0x0C20_0000, 0x4000),  // PLIC 各核的阈值与 claim
+    (0x3800_0000, 0x1000),  // UARTHS
+    (0x3800_1000, 0x1000),  // GPIOHS
+    (0x5020_0000, 0x1000),  // GPIO
+    (0x5024_0000, 0x1000),  // SPI_SLAVE
+    (0x502B_0000, 0x1000),  // FPIOA
+    (0x502D_0000, 0x1000),  // TIMER0
+    (0x502E_0000, 0x1000),  // TIMER1
+    (0x502F_0000, 0x1000),  // TIMER2
+    (0x5044_0000, 0x1000),  // SYSCTL
+    (0x5200_0000, 0x1000),  // SPI0
+    (0x5300_0000, 0x1000),  // SPI1
+    (0x5400_0000, 0x1000),  // SPI2
+];
+/// Goldfish RTC 的物理地址，设备树不可用时使用，须位于 MMIO 中
+#[cfg(not(feature = "k210"))]
+pub const GOLDFISH_RTC: Option<usize> = Some(0x0010_1000);
+#[cfg(feature = "k210")]
+pub const GOLDFISH_RTC: Option<usize> = None;
+/// NS16550A 串口的物理地址与中断号，设备树不可用时使用，须位于 MMIO 中
+#[cfg(not(feature = "k210"))]
+pub const UART: Option<(usize, u32)> = Some((0x1000_0000, 10));
+#[cfg(feature = "k210")]
+pub const UART: Option<(usize, u32)> = None;
+/// virtio-mmio 设备的物理地址与中断号，设备树不可用时使用，须位于 MMIO 中
+#[cfg(not(feature = "k210"))]
+pub const VIRTIO: &[(usize, u32)] = &[(0x1000_1000, 1)];
+#[cfg(feature = "k210")]
+pub const VIRTIO: &[(usize, u32)] = &[];
+/// PLIC 的物理地址，设备树不可用时使用，须位于 MMIO 中
+pub const PLIC: Option<usize> = Some(0x0C00_0000);
 /// 时钟频率
+#[cfg(not(feature = "k210"))]
 pub const CLOCK_FREQ: u64 = 10_000_000;
+#[cfg(feature = "k210")]
+pub const CLOCK_FREQ: u64 = 403_000_000 / 62;
 /// boot cpu id
 pub const BOOT_CPU_ID: usize = 0;
+/// 最大核数，决定启动栈与紧急中断栈的个数
+#[cfg(not(feature = "k210"))]
+pub const CPU_NUM: usize = 4;
+#[cfg(feature = "k210")]
+pub const CPU_NUM: usize = 2;
+/// 每个核的启动栈大小
+pub const BOOT_STACK_SIZE: usize = 4096 * 16;
 pub const PAGE_SIZE: usize = 0x1000;
 
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
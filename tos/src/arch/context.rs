@@ -1,21 +1,22 @@
-use riscv::register::{scause::Scause, sstatus::Sstatus};
-
-#[repr(C)]
-pub struct TrapFrame {
-    pub x: [usize; 32],
-    pub sstatus: Sstatus,
-    pub sepc: usize,
-}
+//! 线程切换时保存的上下文
+use super::interface::TaskContext;
 
 #[repr(C)]
+#[derive(Default)]
 pub struct TaskContextImpl {
     pub ra: usize,
     satp: usize,
     s: [usize; 12],
 }
-impl TaskContextImpl {
-    pub fn set_ra(&mut self, value: usize) -> &mut Self {
+
+impl TaskContext for TaskContextImpl {
+    fn set_ra(&mut self, value: usize) -> &mut Self {
         self.ra = value;
         self
     }
+
+    fn set_token(&mut self, token: usize) -> &mut Self {
+        self.satp = token;
+        self
+    }
 }
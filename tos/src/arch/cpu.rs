@@ -0,0 +1,43 @@
+//! 多核相关
+use super::config::{CPU_NUM, KERNEL_MAP_OFFSET};
+use super::interface::Cpu;
+use super::sbi::{hart_start, SbiError};
+
+/// 获取当前核的 id，启动时保存在 tp 寄存器中
+#[inline(always)]
+pub fn get_cpu_id() -> usize {
+    let cpu_id;
+    unsafe {
+        asm!("mv {0}, tp", out(reg) cpu_id);
+    }
+    cpu_id
+}
+
+pub struct CpuImpl;
+
+impl Cpu for CpuImpl {
+    const CPU_NUM: usize = CPU_NUM;
+
+    fn id() -> usize {
+        get_cpu_id()
+    }
+
+    fn wait_for_interrupt() {
+        unsafe { riscv::asm::wfi() }
+    }
+}
+
+/// 通过 SBI HSM 扩展唤醒除 `boot_cpu_id` 以外的核，从核从 `_start_secondary` 开始执行
+pub fn boot_secondary_cpus(boot_cpu_id: usize) {
+    extern "C" {
+        fn _start_secondary();
+    }
+    let entry = _start_secondary as usize - KERNEL_MAP_OFFSET;
+    for cpu_id in (0..CPU_NUM).filter(|&id| id != boot_cpu_id) {
+        match hart_start(cpu_id, entry, 0).into_result() {
+            // 该核已经在运行
+            Ok(_) | Err(SbiError::AlreadyAvailable) => {}
+            Err(error) => warn!("failed to start hart {}: {:?}", cpu_id, error),
+        }
+    }
+}
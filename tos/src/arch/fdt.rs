@@ -0,0 +1,285 @@
+//! 扁平设备树（Flattened Device Tree）解析
+//!
+//! 只读、不使用堆，可以在内存初始化之前使用
+use core::convert::TryInto;
+use core::str::from_utf8;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// 节点嵌套的最大深度
+const MAX_DEPTH: usize = 16;
+
+/// 读取大端序的 u32
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+/// 按 4 字节对齐
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+/// 读取以 0 结尾的字符串
+fn cstr(data: &[u8], offset: usize) -> Option<&str> {
+    let bytes = data.get(offset..)?;
+    let len = bytes.iter().position(|&b| b == 0)?;
+    from_utf8(&bytes[..len]).ok()
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt {
+    data: &'static [u8],
+    struct_offset: usize,
+    strings_offset: usize,
+}
+
+impl Fdt {
+    /// 从 `va` 处解析设备树，魔数或大小不正确时返回 None
+    pub unsafe fn from_ptr(va: usize) -> Option<Self> {
+        let header = core::slice::from_raw_parts(va as *const u8, 40);
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let total_size = be32(header, 4)? as usize;
+        Self::from_bytes(core::slice::from_raw_parts(va as *const u8, total_size))
+    }
+
+    pub fn from_bytes(data: &'static [u8]) -> Option<Self> {
+        if be32(data, 0)? != FDT_MAGIC || be32(data, 4)? as usize > data.len() {
+            return None;
+        }
+        let struct_offset = be32(data, 8)? as usize;
+        let strings_offset = be32(data, 12)? as usize;
+        if struct_offset >= data.len() || strings_offset >= data.len() {
+            return None;
+        }
+        Some(Self {
+            data,
+            struct_offset,
+            strings_offset,
+        })
+    }
+
+    /// 设备树的总大小
+    pub fn total_size(&self) -> usize {
+        be32(self.data, 4).unwrap() as usize
+    }
+
+    pub fn as_bytes(&self) -> &'static [u8] {
+        self.data
+    }
+
+    /// 按深度优先的顺序遍历所有节点
+    pub fn nodes(&self) -> NodeIter {
+        NodeIter {
+            fdt: *self,
+            offset: self.struct_offset,
+            depth: 0,
+            cells: [(2, 1); MAX_DEPTH],
+        }
+    }
+
+    /// 按路径查找节点，如 `/chosen`
+    pub fn find_node(&self, path: &str) -> Option<Node> {
+        let mut components = path.split('/').filter(|c| !c.is_empty());
+        let mut target = components.next();
+        let mut target_depth = 1;
+        if target.is_none() {
+            return self.nodes().next();
+        }
+        // 跳过根节点
+        for node in self.nodes().skip(1) {
+            if node.depth < target_depth {
+                // 已经离开了匹配的父节点
+                return None;
+            }
+            if node.depth == target_depth && node.name_matches(target.unwrap()) {
+                target = components.next();
+                target_depth += 1;
+                if target.is_none() {
+                    return Some(node);
+                }
+            }
+        }
+        None
+    }
+
+    /// 查找第一个兼容 `compatible` 的节点
+    pub fn find_compatible(&self, compatible: &str) -> Option<Node> {
+        self.nodes().find(|node| node.is_compatible(compatible))
+    }
+
+    fn string_at(&self, offset: usize) -> Option<&'static str> {
+        cstr(self.data, self.strings_offset + offset)
+    }
+}
+
+/// 设备树中的一个节点
+#[derive(Clone, Copy)]
+pub struct Node {
+    fdt: Fdt,
+    pub name: &'static str,
+    /// 根节点深度为 0
+    pub depth: usize,
+    /// 第一个属性的偏移
+    props_offset: usize,
+    /// 父节点的 (#address-cells, #size-cells)
+    parent_cells: (u32, u32),
+}
+
+impl Node {
+    /// 遍历该节点的属性
+    pub fn props(&self) -> PropIter {
+        PropIter {
+            fdt: self.fdt,
+            offset: self.props_offset,
+        }
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&'static [u8]> {
+        self.props()
+            .find(|(prop_name, _)| *prop_name == name)
+            .map(|(_, value)| value)
+    }
+
+    /// 读取值为 u32 的属性
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        self.prop(name).and_then(|value| be32(value, 0))
+    }
+
+    /// 读取值为字符串的属性
+    pub fn prop_str(&self, name: &str) -> Option<&'static str> {
+        self.prop(name).and_then(|value| cstr(value, 0))
+    }
+
+    /// `compatible` 属性中的所有字符串
+    pub fn compatible(&self) -> impl Iterator<Item = &'static str> {
+        self.prop("compatible")
+            .unwrap_or(&[])
+            .split(|&b| b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| from_utf8(s).ok())
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().any(|c| c == compatible)
+    }
+
+    /// `reg` 属性中的第 `index` 个 (地址, 大小)
+    pub fn reg(&self, index: usize) -> Option<(usize, usize)> {
+        let (address_cells, size_cells) = self.parent_cells;
+        let entry_size = (address_cells + size_cells) as usize * 4;
+        let reg = self.prop("reg")?;
+        let entry = reg.get(index * entry_size..(index + 1) * entry_size)?;
+        let read_cells = |bytes: &[u8], cells: u32| {
+            (0..cells as usize).fold(0usize, |acc, i| {
+                acc << 32 | be32(bytes, i * 4).unwrap() as usize
+            })
+        };
+        let address = read_cells(entry, address_cells);
+        let size = read_cells(&entry[address_cells as usize * 4..], size_cells);
+        Some((address, size))
+    }
+
+    /// `interrupts` 属性中的第一个中断号
+    pub fn interrupt(&self) -> Option<u32> {
+        self.prop_u32("interrupts")
+    }
+
+    /// 节点名是否为 `name`，`name` 中不含 `@` 时忽略单元地址
+    fn name_matches(&self, name: &str) -> bool {
+        self.name == name || (!name.contains('@') && self.name.split('@').next() == Some(name))
+    }
+}
+
+pub struct NodeIter {
+    fdt: Fdt,
+    offset: usize,
+    depth: usize,
+    cells: [(u32, u32); MAX_DEPTH],
+}
+
+impl Iterator for NodeIter {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Node> {
+        let data = self.fdt.data;
+        loop {
+            let token = be32(data, self.offset)?;
+            self.offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(data, self.offset)?;
+                    self.offset = align4(self.offset + name.len() + 1);
+                    if self.depth >= MAX_DEPTH {
+                        return None;
+                    }
+                    let parent_cells = if self.depth == 0 {
+                        (2, 1)
+                    } else {
+                        self.cells[self.depth - 1]
+                    };
+                    let node = Node {
+                        fdt: self.fdt,
+                        name,
+                        depth: self.depth,
+                        props_offset: self.offset,
+                        parent_cells,
+                    };
+                    // 该节点声明的 cells 作用于其子节点
+                    self.cells[self.depth] = (
+                        node.prop_u32("#address-cells").unwrap_or(2),
+                        node.prop_u32("#size-cells").unwrap_or(1),
+                    );
+                    self.depth += 1;
+                    return Some(node);
+                }
+                FDT_END_NODE => {
+                    self.depth = self.depth.checked_sub(1)?;
+                }
+                FDT_PROP => {
+                    let len = be32(data, self.offset)? as usize;
+                    self.offset = align4(self.offset + 8 + len);
+                }
+                FDT_NOP => {}
+                FDT_END => return None,
+                _ => return None,
+            }
+        }
+    }
+}
+
+pub struct PropIter {
+    fdt: Fdt,
+    offset: usize,
+}
+
+impl Iterator for PropIter {
+    type Item = (&'static str, &'static [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let data = self.fdt.data;
+        loop {
+            let token = be32(data, self.offset)?;
+            match token {
+                FDT_PROP => {
+                    let len = be32(data, self.offset + 4)? as usize;
+                    let name_offset = be32(data, self.offset + 8)? as usize;
+                    let value_offset = self.offset + 12;
+                    let value = data.get(value_offset..value_offset + len)?;
+                    self.offset = align4(value_offset + len);
+                    return Some((self.fdt.string_at(name_offset)?, value));
+                }
+                FDT_NOP => self.offset += 4,
+                // 属性之后是子节点或节点的结束
+                _ => return None,
+            }
+        }
+    }
+}
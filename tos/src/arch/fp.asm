@@ -0,0 +1,38 @@
+# 保存/恢复浮点寄存器。内核的目标可能不含 F/D 扩展，指令用 .word 编码
+.altmacro
+
+# 宏：fsd f\n, \n*8(a0)
+.macro FSD n
+    .word ((\n*8 >> 5) << 25) | (\n << 20) | (10 << 15) | (3 << 12) | ((\n*8 & 0x1f) << 7) | 0x27
+.endm
+# 宏：fld f\n, \n*8(a0)
+.macro FLD n
+    .word (\n*8 << 20) | (10 << 15) | (3 << 12) | (\n << 7) | 0x07
+.endm
+
+    .section .text
+    .align 2
+    .globl __fp_save
+# 将 f0~f31 与 fcsr 保存到 a0 指向的 FpState，调用时 sstatus.FS 不能为 Off
+__fp_save:
+    .set    n, 0
+    .rept   32
+        FSD %n
+        .set    n, n + 1
+    .endr
+    csrr    t0, 0x003           # fcsr
+    sd      t0, 32*8(a0)
+    ret
+
+    .align 2
+    .globl __fp_restore
+# 从 a0 指向的 FpState 恢复 f0~f31 与 fcsr，调用时 sstatus.FS 不能为 Off
+__fp_restore:
+    .set    n, 0
+    .rept   32
+        FLD %n
+        .set    n, n + 1
+    .endr
+    ld      t0, 32*8(a0)
+    csrw    0x003, t0           # fcsr
+    ret
@@ -0,0 +1,90 @@
+//! 用户线程浮点寄存器的惰性保存与恢复
+//!
+//! 内核自身不使用浮点寄存器，进入内核时其中仍是当前线程的状态，`__trap` 不保存它们。
+//! 浮点状态 `FpState` 每个用户线程一份，放在其内核栈顶，即从用户态进入内核时的 TrapFrame 之上；
+//! 内核态中嵌套的中断不涉及用户的浮点寄存器，其 TrapFrame 不带浮点状态。
+//! 线程换出时，只有 sstatus.FS 为 Dirty 才保存；
+//! 换入时若寄存器中已不是它的状态，则将 FS 置为 Off，
+//! 等它再次使用浮点指令触发非法指令异常时再恢复
+use core::intrinsics::transmute;
+use core::mem::size_of;
+
+use super::config::CPU_NUM;
+use super::cpu::get_cpu_id;
+use super::trap_context::TrapFrameImpl;
+use riscv::register::sstatus::{self, Sstatus, FS, SPP};
+
+global_asm!(include_str!("./fp.asm"));
+extern "C" {
+    fn __fp_save(state: *mut FpState);
+    fn __fp_restore(state: *const FpState);
+}
+
+/// 浮点寄存器 f0~f31 与 fcsr
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FpState {
+    pub f: [usize; 32],
+    pub fcsr: usize,
+    /// 最近一次加载到哪个核的浮点寄存器中，为核号加 1，0 表示从未加载
+    loaded_on: usize,
+}
+
+/// 用户线程的内核栈顶须为 `FpState` 预留的大小，进入用户态前 sscratch 指向预留区域之下
+pub const FP_STATE_SIZE: usize = size_of::<FpState>();
+// 预留区域之下的 TrapFrame 需要保持 16 字节对齐
+const _: () = assert!(FP_STATE_SIZE % 16 == 0);
+
+/// 从用户态进入内核时的 TrapFrame 对应线程的浮点状态，位于 TrapFrame 之上
+pub(super) fn fp_state(trap_frame: &mut TrapFrameImpl) -> &mut FpState {
+    debug_assert_eq!(trap_frame.sstatus.spp(), SPP::User);
+    unsafe { &mut *((trap_frame as *mut TrapFrameImpl).add(1) as *mut FpState) }
+}
+
+/// 各核的浮点寄存器当前属于哪个 TrapFrame
+static mut OWNER: [usize; CPU_NUM] = [0; CPU_NUM];
+
+pub(super) fn set_fs(sstatus: &mut Sstatus, fs: FS) {
+    unsafe {
+        let bits = transmute::<_, usize>(*sstatus) & !(0b11 << 13) | (fs as usize) << 13;
+        *sstatus = transmute::<_, Sstatus>(bits);
+    }
+}
+
+/// 线程换出时调用，浮点寄存器被修改过时保存
+pub fn switch_out(trap_frame: &mut TrapFrameImpl) {
+    if trap_frame.sstatus.fs() != FS::Dirty {
+        return;
+    }
+    unsafe {
+        sstatus::set_fs(FS::Clean);
+        __fp_save(fp_state(trap_frame));
+    }
+    set_fs(&mut trap_frame.sstatus, FS::Clean);
+}
+
+/// 线程换入时调用，浮点寄存器中不是该线程的状态时，推迟到它使用浮点指令时恢复
+pub fn switch_in(trap_frame: &mut TrapFrameImpl) {
+    let cpu_id = get_cpu_id();
+    let owned = unsafe { OWNER[cpu_id] } == trap_frame as *const _ as usize
+        && fp_state(trap_frame).loaded_on == cpu_id + 1;
+    if !owned {
+        set_fs(&mut trap_frame.sstatus, FS::Off);
+    }
+}
+
+/// 用户态的非法指令异常，FS 为 Off 时恢复浮点寄存器，返回 true 表示重新执行该指令即可
+pub fn handle_fp_trap(trap_frame: &mut TrapFrameImpl) -> bool {
+    if trap_frame.sstatus.fs() != FS::Off {
+        return false;
+    }
+    let cpu_id = get_cpu_id();
+    unsafe {
+        sstatus::set_fs(FS::Clean);
+        __fp_restore(fp_state(trap_frame));
+        OWNER[cpu_id] = trap_frame as *const _ as usize;
+    }
+    fp_state(trap_frame).loaded_on = cpu_id + 1;
+    set_fs(&mut trap_frame.sstatus, FS::Clean);
+    true
+}
@@ -0,0 +1,216 @@
+//! 体系结构相关代码向内核提供的统一接口
+//!
+//! 内核只通过这些 trait 使用 `arch` 中的 `*Impl` 类型，
+//! 新增后端（如用于测试的主机后端）时实现同样的一组类型即可
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::ops::Range;
+
+/// 读取当前的通用寄存器
+pub trait Register {
+    /// 栈指针
+    fn sp() -> usize;
+
+    /// 帧指针
+    fn fp() -> usize;
+
+    /// 返回地址
+    fn ra() -> usize;
+}
+
+/// 发生中断时保存的现场
+pub trait TrapFrame: Default {
+    /// 获取栈指针
+    fn sp(&self) -> usize;
+
+    /// 设置栈指针
+    fn set_sp(&mut self, value: usize) -> &mut Self;
+
+    /// 获取返回地址
+    fn ra(&self) -> usize;
+
+    /// 设置返回地址
+    fn set_ra(&mut self, value: usize) -> &mut Self;
+
+    /// 设置返回值
+    fn set_return_value(&mut self, value: usize) -> &mut Self;
+
+    /// 设置入口
+    fn set_entry_point(&mut self, value: usize) -> &mut Self;
+
+    /// 按照函数调用规则写入参数
+    fn set_arguments(&mut self, arguments: &[usize]) -> &mut Self;
+
+    /// 设置返回用户态时使用的页表，仅在用户与内核使用不同页表时有效
+    fn set_token(&mut self, token: usize) -> &mut Self;
+
+    /// 线程换出时调用，浮点寄存器被修改过时保存
+    fn switch_out_fp(&mut self);
+
+    /// 线程换入时调用，浮点寄存器在该线程再次使用时才恢复
+    fn switch_in_fp(&mut self);
+
+    /// 为线程构建初始 `TrapFrame`
+    fn init(
+        &mut self,
+        stack_top: usize,
+        entry_point: usize,
+        arguments: Option<&[usize]>,
+        is_user: bool,
+    );
+}
+
+/// 中断处理
+pub trait Trap {
+    /// 设置中断入口并开启中断，每个核都需要调用
+    fn init();
+}
+
+/// 线程切换时保存的上下文
+pub trait TaskContext: Default {
+    /// 设置切换后跳转到的地址
+    fn set_ra(&mut self, value: usize) -> &mut Self;
+
+    /// 设置切换后使用的页表
+    fn set_token(&mut self, token: usize) -> &mut Self;
+}
+
+bitflags! {
+    /// 页表项的标志位，与具体体系结构的编码无关，由 `PageTableEntry` 转换
+    pub struct PTEFlags: u8 {
+        const E =      0;
+        /// 有效
+        const V = 1 << 0;
+        const R = 1 << 1;
+        const W = 1 << 2;
+        const X = 1 << 3;
+        /// 用户态可访问
+        const U = 1 << 4;
+        /// 所有地址空间共有
+        const G = 1 << 5;
+        /// 访问位
+        const A = 1 << 6;
+        /// 脏位
+        const D = 1 << 7;
+    }
+}
+
+/// 页表项，内核通过它读写页表而不依赖具体的编码
+pub trait PageTableEntry: Copy {
+    /// 指向物理页 `ppn` 的页表项
+    fn new(ppn: usize, flags: PTEFlags) -> Self;
+
+    /// 无效的页表项
+    fn empty() -> Self;
+
+    /// 指向的物理页号
+    fn ppn(&self) -> usize;
+
+    /// 标志位
+    fn flags(&self) -> PTEFlags;
+
+    /// 是否有效
+    fn is_valid(&self) -> bool {
+        self.flags().contains(PTEFlags::V)
+    }
+}
+
+/// 页表的格式、地址空间的布局，页表的激活与 TLB 的刷新
+pub trait Mmu {
+    /// 页表项
+    type Pte: PageTableEntry;
+
+    /// 支持的最大页表级数
+    const MAX_LEVELS: usize;
+
+    /// 页大小为 1 << PAGE_SIZE_BITS，每个页表占一页
+    const PAGE_SIZE_BITS: usize;
+
+    /// 虚拟页号中每级页表索引的位数
+    const INDEX_BITS: usize;
+
+    /// 内核线性映射的偏移量，物理地址 pa 映射到 pa + KERNEL_MAP_OFFSET
+    const KERNEL_MAP_OFFSET: usize;
+
+    /// 跳板页的虚拟地址，每个地址空间都需要映射
+    const TRAMPOLINE: usize;
+
+    /// 最高处的内核栈的栈顶
+    const KERNEL_STACK_TOP: usize;
+
+    /// 用户地址空间的上界
+    const USER_SPACE_END: usize;
+
+    /// 选择分页模式，由主核在建立内核页表之前调用
+    fn init();
+
+    /// 当前分页模式下页表的级数
+    fn levels() -> usize;
+
+    /// 由根页表的物理页号得到页表令牌
+    fn token(root_ppn: usize) -> usize;
+
+    /// 当前使用的页表令牌
+    fn active_token() -> usize;
+
+    /// 切换页表，不刷新 TLB
+    unsafe fn set_token(token: usize);
+
+    /// 刷新当前核的全部 TLB
+    fn flush_tlb();
+
+    /// 刷新当前核中 `va` 所在页的 TLB
+    fn flush_tlb_va(va: usize);
+
+    /// 刷新其他核中 `va_range` 的 TLB
+    fn flush_tlb_others(va_range: Range<usize>);
+
+    /// 切换到 `token` 对应的页表，与当前页表相同时不做任何事
+    unsafe fn activate(token: usize) {
+        if Self::active_token() != token {
+            Self::set_token(token);
+            // 别忘了刷新 TLB!
+            Self::flush_tlb();
+        }
+    }
+}
+
+/// 当前核的中断开关
+pub trait Interrupt {
+    /// 开启中断
+    unsafe fn enable();
+
+    /// 关闭中断，返回之前是否开启
+    unsafe fn disable() -> bool;
+
+    /// 中断是否开启
+    fn is_enabled() -> bool;
+
+    /// 恢复 `disable` 之前的状态
+    unsafe fn restore(enabled: bool) {
+        if enabled {
+            Self::enable();
+        }
+    }
+}
+
+/// 处理器
+pub trait Cpu {
+    /// 最大核数，核的 id 都小于它
+    const CPU_NUM: usize;
+
+    /// 当前核的 id
+    fn id() -> usize;
+
+    /// 等待中断
+    fn wait_for_interrupt();
+}
+
+/// 启动时探测到的物理内存与设备
+pub trait Machine {
+    /// 物理内存的结束虚拟地址
+    fn memory_end() -> usize;
+
+    /// 需要映射到内核地址空间的 MMIO 区域 (物理地址, 大小)
+    fn mmio_regions() -> Vec<(usize, usize)>;
+}
@@ -0,0 +1,21 @@
+//! 当前核的中断开关
+use super::interface::Interrupt;
+use riscv::register::sstatus;
+
+pub struct InterruptImpl;
+
+impl Interrupt for InterruptImpl {
+    unsafe fn enable() {
+        sstatus::set_sie();
+    }
+
+    unsafe fn disable() -> bool {
+        let enabled = Self::is_enabled();
+        sstatus::clear_sie();
+        enabled
+    }
+
+    fn is_enabled() -> bool {
+        sstatus::read().sie()
+    }
+}
@@ -0,0 +1,155 @@
+//! 启动时从设备树中获取的机器信息
+//!
+//! 设备树不可用时使用 `config` 中的默认值
+use super::config::{KERNEL_MAP_OFFSET, MEMORY_END, MEMORY_START, MMIO};
+use super::fdt::Fdt;
+use super::interface::Machine;
+use alloc::vec::Vec;
+
+/// 设备树的最大大小
+const MAX_FDT_SIZE: usize = 0x1_0000;
+/// 最多记录的 virtio-mmio 设备数
+pub const MAX_VIRTIO_NUM: usize = 8;
+
+/// 设备的 MMIO 区域与中断号
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceInfo {
+    /// MMIO 物理地址
+    pub base: usize,
+    pub size: usize,
+    /// PLIC 中断号
+    pub irq: Option<u32>,
+}
+
+pub struct MachineInfo {
+    /// 物理内存的起始虚拟地址
+    pub memory_start: usize,
+    /// 物理内存的结束虚拟地址
+    pub memory_end: usize,
+    pub uart: Option<DeviceInfo>,
+    pub plic: Option<DeviceInfo>,
+    pub rtc: Option<DeviceInfo>,
+    pub virtio: [Option<DeviceInfo>; MAX_VIRTIO_NUM],
+    /// 启动参数 /chosen/bootargs
+    pub bootargs: &'static str,
+    /// 复制到内核中的设备树
+    pub fdt: Option<Fdt>,
+}
+
+impl MachineInfo {
+    /// 需要映射到内核地址空间的 MMIO 区域 (物理地址, 大小)，没有设备树时使用 `config::MMIO`
+    pub fn mmio_regions(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let default: &[(usize, usize)] = if self.fdt.is_none() { MMIO } else { &[] };
+        [self.uart, self.plic, self.rtc]
+            .into_iter()
+            .chain(self.virtio.iter().copied())
+            .flatten()
+            .map(|device| (device.base, device.size))
+            .chain(default.iter().copied())
+    }
+}
+
+/// 设备树原本所在的物理页会被帧分配器回收，需要复制一份
+#[repr(align(8))]
+struct FdtSpace([u8; MAX_FDT_SIZE]);
+static mut FDT_SPACE: FdtSpace = FdtSpace([0; MAX_FDT_SIZE]);
+
+static mut MACHINE: MachineInfo = MachineInfo {
+    memory_start: MEMORY_START,
+    memory_end: MEMORY_END,
+    uart: None,
+    plic: None,
+    rtc: None,
+    virtio: [None; MAX_VIRTIO_NUM],
+    bootargs: "",
+    fdt: None,
+};
+
+pub fn machine() -> &'static MachineInfo {
+    unsafe { &MACHINE }
+}
+
+pub struct MachineImpl;
+
+impl Machine for MachineImpl {
+    fn memory_end() -> usize {
+        machine().memory_end
+    }
+
+    fn mmio_regions() -> Vec<(usize, usize)> {
+        machine().mmio_regions().collect()
+    }
+}
+
+/// 解析 SBI 通过 a1 传入的设备树，须在内存初始化之前由主核调用
+pub fn init(dtb_pa: usize) {
+    let fdt = match unsafe { copy_fdt(dtb_pa) } {
+        Some(fdt) => fdt,
+        None => {
+            warn!("no valid device tree at {:#x}, use default config", dtb_pa);
+            return;
+        }
+    };
+    let machine = unsafe { &mut MACHINE };
+    machine.fdt = Some(fdt);
+
+    if let Some((base, size)) = fdt
+        .nodes()
+        .find(|node| node.prop_str("device_type") == Some("memory"))
+        .and_then(|node| node.reg(0))
+    {
+        machine.memory_start = base + KERNEL_MAP_OFFSET;
+        machine.memory_end = base + size + KERNEL_MAP_OFFSET;
+    }
+
+    let mut virtio_num = 0;
+    for node in fdt.nodes() {
+        let device = match node.reg(0) {
+            Some((base, size)) => DeviceInfo {
+                base,
+                size,
+                irq: node.interrupt(),
+            },
+            None => continue,
+        };
+        if node.is_compatible("virtio,mmio") {
+            if virtio_num < MAX_VIRTIO_NUM {
+                machine.virtio[virtio_num] = Some(device);
+                virtio_num += 1;
+            }
+        } else if node.is_compatible("ns16550a") {
+            machine.uart.get_or_insert(device);
+        } else if node.is_compatible("riscv,plic0") || node.is_compatible("sifive,plic-1.0.0") {
+            machine.plic.get_or_insert(device);
+        } else if node.is_compatible("google,goldfish-rtc") {
+            machine.rtc.get_or_insert(device);
+        }
+    }
+
+    if let Some(bootargs) = fdt
+        .find_node("/chosen")
+        .and_then(|node| node.prop_str("bootargs"))
+    {
+        machine.bootargs = bootargs;
+    }
+
+    info!(
+        "memory [{:#x}, {:#x}), bootargs: \"{}\"",
+        machine.memory_start, machine.memory_end, machine.bootargs
+    );
+}
+
+/// 将设备树复制到 FDT_SPACE 中
+unsafe fn copy_fdt(dtb_pa: usize) -> Option<Fdt> {
+    if dtb_pa == 0 {
+        return None;
+    }
+    let fdt = Fdt::from_ptr(dtb_pa + KERNEL_MAP_OFFSET)?;
+    let size = fdt.total_size();
+    if size > MAX_FDT_SIZE {
+        warn!("device tree is too large: {:#x} bytes", size);
+        return None;
+    }
+    FDT_SPACE.0[..size].copy_from_slice(fdt.as_bytes());
+    Fdt::from_bytes(&FDT_SPACE.0[..size])
+}
@@ -1,9 +1,29 @@
+//! 体系结构相关的代码
+//!
+//! 内核通过 `interface` 中的 trait 使用下面导出的 `*Impl` 类型
+pub mod backtrace;
 pub mod config;
 pub mod context;
-pub mod logger;
+pub mod cpu;
+pub mod fdt;
+pub mod fp;
+pub mod interface;
+pub mod interrupt;
+pub mod machine;
+pub mod paging;
+pub mod register;
 pub mod sbi;
 pub mod timer;
 pub mod trap;
 pub mod trap_context;
-use riscv::register::{scause::Scause, sstatus::Sstatus};
-//TODO 统一底层接口
+#[cfg(feature = "trap-stats")]
+pub mod trap_stats;
+
+pub use context::TaskContextImpl;
+pub use cpu::CpuImpl;
+pub use interrupt::InterruptImpl;
+pub use machine::MachineImpl;
+pub use paging::{MmuImpl, PageTableEntryImpl, PagingMode};
+pub use register::RegisterImpl;
+pub use trap::TrapImpl;
+pub use trap_context::TrapFrameImpl;
@@ -0,0 +1,180 @@
+//! Sv39/Sv48 的页表格式，页表的激活与 TLB 的刷新
+//!
+//! 主核启动时探测硬件是否支持 Sv48，不支持时使用 Sv39
+use super::config::{
+    CPU_NUM, KERNEL_MAP_OFFSET, KERNEL_STACK_TOP, PAGE_SIZE, PAGE_SIZE_BITS, TRAMPOLINE,
+    USER_SPACE_END,
+};
+use super::cpu::get_cpu_id;
+use super::interface::{Mmu, PTEFlags, PageTableEntry};
+use super::sbi::remote_sfence_vma;
+use core::ops::Range;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::asm::sfence_vma_all;
+use riscv::register::satp;
+
+/// satp 中的分页模式
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PagingMode {
+    Sv39 = 8,
+    Sv48 = 9,
+}
+
+impl PagingMode {
+    /// 页表的级数
+    pub fn levels(&self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+        }
+    }
+}
+
+/// 页表项中物理页号的位置与位数
+const PTE_PPN_SHIFT: usize = 10;
+const PTE_PPN_BITS: usize = 44;
+
+/// 各标志位在页表项中的位置
+const PTE_FLAG_BITS: [(PTEFlags, usize); 8] = [
+    (PTEFlags::V, 0),
+    (PTEFlags::R, 1),
+    (PTEFlags::W, 2),
+    (PTEFlags::X, 3),
+    (PTEFlags::U, 4),
+    (PTEFlags::G, 5),
+    (PTEFlags::A, 6),
+    (PTEFlags::D, 7),
+];
+
+/// Sv39/Sv48 的页表项，两者格式相同
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct PageTableEntryImpl(usize);
+
+impl PageTableEntry for PageTableEntryImpl {
+    fn new(ppn: usize, flags: PTEFlags) -> Self {
+        let bits = PTE_FLAG_BITS
+            .iter()
+            .filter(|(flag, _)| flags.contains(*flag))
+            .fold(0, |bits, (_, bit)| bits | 1 << bit);
+        Self(ppn << PTE_PPN_SHIFT | bits)
+    }
+
+    fn empty() -> Self {
+        Self(0)
+    }
+
+    fn ppn(&self) -> usize {
+        self.0 >> PTE_PPN_SHIFT & ((1 << PTE_PPN_BITS) - 1)
+    }
+
+    fn flags(&self) -> PTEFlags {
+        PTE_FLAG_BITS
+            .iter()
+            .filter(|(_, bit)| self.0 & 1 << bit != 0)
+            .fold(PTEFlags::empty(), |flags, (flag, _)| flags | *flag)
+    }
+}
+
+/// 当前使用的分页模式，启动时为 entry.asm 中设置的 Sv39
+static PAGING_MODE: AtomicUsize = AtomicUsize::new(PagingMode::Sv39 as usize);
+
+pub fn paging_mode() -> PagingMode {
+    match PAGING_MODE.load(Ordering::Relaxed) {
+        9 => PagingMode::Sv48,
+        _ => PagingMode::Sv39,
+    }
+}
+
+/// 探测 Sv48 时使用的根页表
+#[repr(C, align(4096))]
+struct ProbeTable([usize; PAGE_SIZE / 8]);
+static mut PROBE_TABLE: ProbeTable = ProbeTable([0; PAGE_SIZE / 8]);
+
+/// 尝试将 satp 切换为 Sv48，写入不支持的模式时 satp 保持不变
+///
+/// 探测用的 Sv48 根页表中低地址和高地址的第一项都指向 entry.asm 中的 Sv39 根页表，
+/// 后者的表项在 Sv48 中作为第二级的 1G 大页，映射关系与切换前相同
+#[cfg(not(feature = "k210"))]
+unsafe fn probe_sv48() -> bool {
+    let sv39_token = satp::read().bits();
+    let sv39_root = (sv39_token & ((1 << 44) - 1)) << 12;
+    // 非叶子节点只设置 V 位
+    let entry = (sv39_root >> 12) << 10 | 1;
+    PROBE_TABLE.0[0] = entry;
+    PROBE_TABLE.0[511] = entry;
+    let root_ppn = (PROBE_TABLE.0.as_ptr() as usize - KERNEL_MAP_OFFSET) >> 12;
+    let sv48_token = (PagingMode::Sv48 as usize) << 60 | root_ppn;
+    asm!("csrw satp, {0}", in(reg) sv48_token);
+    sfence_vma_all();
+    let supported = satp::read().bits() == sv48_token;
+    asm!("csrw satp, {0}", in(reg) sv39_token);
+    sfence_vma_all();
+    supported
+}
+
+/// k210 遵循 1.9.1 版特权级规范，没有 satp 的模式位，只使用 Sv39
+#[cfg(feature = "k210")]
+unsafe fn probe_sv48() -> bool {
+    false
+}
+
+pub struct MmuImpl;
+
+impl Mmu for MmuImpl {
+    type Pte = PageTableEntryImpl;
+
+    const MAX_LEVELS: usize = 4;
+
+    const PAGE_SIZE_BITS: usize = PAGE_SIZE_BITS;
+
+    const INDEX_BITS: usize = 9;
+
+    const KERNEL_MAP_OFFSET: usize = KERNEL_MAP_OFFSET;
+
+    const TRAMPOLINE: usize = TRAMPOLINE;
+
+    const KERNEL_STACK_TOP: usize = KERNEL_STACK_TOP;
+
+    const USER_SPACE_END: usize = USER_SPACE_END;
+
+    fn init() {
+        if unsafe { probe_sv48() } {
+            PAGING_MODE.store(PagingMode::Sv48 as usize, Ordering::Relaxed);
+        }
+        info!("paging mode: {:?}", paging_mode());
+    }
+
+    fn levels() -> usize {
+        paging_mode().levels()
+    }
+
+    fn token(root_ppn: usize) -> usize {
+        (paging_mode() as usize) << 60 | root_ppn
+    }
+
+    fn active_token() -> usize {
+        satp::read().bits()
+    }
+
+    unsafe fn set_token(token: usize) {
+        asm!("csrw satp, {0}", in(reg) token);
+    }
+
+    fn flush_tlb() {
+        unsafe { sfence_vma_all() }
+    }
+
+    fn flush_tlb_va(va: usize) {
+        unsafe {
+            asm!("sfence.vma {0}, zero", in(reg) va);
+        }
+    }
+
+    fn flush_tlb_others(va_range: Range<usize>) {
+        let hart_mask = ((1 << CPU_NUM) - 1) & !(1 << get_cpu_id());
+        if hart_mask != 0 {
+            remote_sfence_vma(hart_mask, 0, va_range.start, va_range.len());
+        }
+    }
+}
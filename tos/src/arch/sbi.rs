@@ -1,5 +1,10 @@
+//! SBI 调用
+//!
+//! 优先使用 v0.2 及以后基于扩展的调用，通过 Base 扩展探测不到时才回退到旧版（v0.1）调用
 #![allow(unused)]
+use core::sync::atomic::{AtomicUsize, Ordering};
 
+// 旧版（v0.1）调用
 const SBI_SET_TIMER: usize = 0;
 const SBI_CONSOLE_PUTCHAR: usize = 1;
 const SBI_CONSOLE_GETCHAR: usize = 2;
@@ -10,8 +15,132 @@ const SBI_REMOTE_SFENCE_VMA: usize = 6;
 const SBI_REMOTE_SFENCE_VMA_ASID: usize = 7;
 const SBI_SHUTDOWN: usize = 8;
 
+// 扩展号
+const EXT_BASE: usize = 0x10;
+const EXT_TIME: usize = 0x5449_4D45;
+const EXT_IPI: usize = 0x73_5049;
+const EXT_RFENCE: usize = 0x5246_4E43;
+const EXT_HSM: usize = 0x48_534D;
+const EXT_SRST: usize = 0x5352_5354;
+
+// Base 扩展的功能号
+const BASE_GET_SPEC_VERSION: usize = 0;
+const BASE_GET_IMPL_ID: usize = 1;
+const BASE_GET_IMPL_VERSION: usize = 2;
+const BASE_PROBE_EXTENSION: usize = 3;
+
+// HSM 扩展的功能号
+const HSM_HART_START: usize = 0;
+const HSM_HART_STOP: usize = 1;
+const HSM_HART_GET_STATUS: usize = 2;
+
+// RFENCE 扩展的功能号
+const RFENCE_FENCE_I: usize = 0;
+const RFENCE_SFENCE_VMA: usize = 1;
+const RFENCE_SFENCE_VMA_ASID: usize = 2;
+
+/// SRST 扩展的复位类型
+pub const RESET_TYPE_SHUTDOWN: usize = 0;
+pub const RESET_TYPE_COLD_REBOOT: usize = 1;
+pub const RESET_TYPE_WARM_REBOOT: usize = 2;
+/// SRST 扩展的复位原因
+pub const RESET_REASON_NO_REASON: usize = 0;
+pub const RESET_REASON_SYSTEM_FAILURE: usize = 1;
+
+const SBI_ERR_NOT_SUPPORTED: isize = -2;
+const SBI_ERR_INVALID_PARAM: isize = -3;
+
+/// SBI 错误码
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SbiError {
+    Failed,
+    NotSupported,
+    InvalidParam,
+    Denied,
+    InvalidAddress,
+    AlreadyAvailable,
+    AlreadyStarted,
+    AlreadyStopped,
+    Unknown(isize),
+}
+
+impl From<isize> for SbiError {
+    fn from(error: isize) -> Self {
+        match error {
+            -1 => Self::Failed,
+            -2 => Self::NotSupported,
+            -3 => Self::InvalidParam,
+            -4 => Self::Denied,
+            -5 => Self::InvalidAddress,
+            -6 => Self::AlreadyAvailable,
+            -7 => Self::AlreadyStarted,
+            -8 => Self::AlreadyStopped,
+            _ => Self::Unknown(error),
+        }
+    }
+}
+
+/// SBI 调用的返回值，a0 为错误码，a1 为返回值
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
+
+impl SbiRet {
+    pub fn is_ok(&self) -> bool {
+        self.error == 0
+    }
+
+    pub fn into_result(self) -> Result<usize, SbiError> {
+        if self.is_ok() {
+            Ok(self.value)
+        } else {
+            Err(self.error.into())
+        }
+    }
+
+    /// 旧版调用没有错误码，视为成功
+    fn legacy(value: usize) -> Self {
+        Self { error: 0, value }
+    }
+
+    fn error(error: isize) -> Self {
+        Self { error, value: 0 }
+    }
+}
+
+/// 硬件线程的状态，见 HSM 扩展
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HartStatus {
+    Started,
+    Stopped,
+    StartPending,
+    StopPending,
+    Suspended,
+    SuspendPending,
+    ResumePending,
+    Unknown(usize),
+}
+
+impl From<usize> for HartStatus {
+    fn from(status: usize) -> Self {
+        match status {
+            0 => Self::Started,
+            1 => Self::Stopped,
+            2 => Self::StartPending,
+            3 => Self::StopPending,
+            4 => Self::Suspended,
+            5 => Self::SuspendPending,
+            6 => Self::ResumePending,
+            _ => Self::Unknown(status),
+        }
+    }
+}
+
 #[inline(always)]
-fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
+fn sbi_legacy_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     let mut ret;
     unsafe {
         asm!(
@@ -25,19 +154,282 @@ fn sbi_call(which: usize, arg0: usize, arg1: usize, arg2: usize) -> usize {
     ret
 }
 
+/// v0.2 及以后的调用方式，a7 为扩展号，a6 为功能号
+#[inline(always)]
+fn sbi_call(
+    eid: usize,
+    fid: usize,
+    arg0: usize,
+    arg1: usize,
+    arg2: usize,
+    arg3: usize,
+    arg4: usize,
+) -> SbiRet {
+    let (error, value);
+    unsafe {
+        asm!(
+            "ecall",
+            inlateout("x10") arg0 => error,
+            inlateout("x11") arg1 => value,
+            in("x12") arg2,
+            in("x13") arg3,
+            in("x14") arg4,
+            in("x16") fid,
+            in("x17") eid,
+        );
+    }
+    SbiRet { error, value }
+}
+
+// 探测到的扩展，PROBED 位表示已经探测过
+const PROBED: usize = 1 << 0;
+const HAS_TIME: usize = 1 << 1;
+const HAS_IPI: usize = 1 << 2;
+const HAS_RFENCE: usize = 1 << 3;
+const HAS_HSM: usize = 1 << 4;
+const HAS_SRST: usize = 1 << 5;
+
+static EXTENSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// 通过 Base 扩展探测各扩展是否可用，结果只探测一次
+fn extensions() -> usize {
+    let extensions = EXTENSIONS.load(Ordering::Relaxed);
+    if extensions & PROBED != 0 {
+        return extensions;
+    }
+    let mut extensions = PROBED;
+    // v0.1 的实现不认识 Base 扩展，此时只能使用旧版调用
+    if get_spec_version().is_ok() {
+        for (eid, bit) in [
+            (EXT_TIME, HAS_TIME),
+            (EXT_IPI, HAS_IPI),
+            (EXT_RFENCE, HAS_RFENCE),
+            (EXT_HSM, HAS_HSM),
+            (EXT_SRST, HAS_SRST),
+        ] {
+            if probe_extension(eid) {
+                extensions |= bit;
+            }
+        }
+    }
+    EXTENSIONS.store(extensions, Ordering::Relaxed);
+    extensions
+}
+
+fn has_extension(bit: usize) -> bool {
+    extensions() & bit != 0
+}
+
+/// 打印 SBI 实现的信息
+pub fn init() {
+    let extensions = extensions();
+    match get_spec_version().into_result() {
+        Ok(version) => info!(
+            "SBI specification v{}.{}, implementation id {:#x} version {:#x}",
+            version >> 24 & 0x7f,
+            version & 0xff_ffff,
+            get_impl_id().value,
+            get_impl_version().value
+        ),
+        Err(_) => info!("SBI v0.1, legacy calls only"),
+    }
+    info!(
+        "SBI extensions: TIME {} IPI {} RFENCE {} HSM {} SRST {}",
+        extensions & HAS_TIME != 0,
+        extensions & HAS_IPI != 0,
+        extensions & HAS_RFENCE != 0,
+        extensions & HAS_HSM != 0,
+        extensions & HAS_SRST != 0
+    );
+}
+
+// Base 扩展
+
+pub fn get_spec_version() -> SbiRet {
+    sbi_call(EXT_BASE, BASE_GET_SPEC_VERSION, 0, 0, 0, 0, 0)
+}
+
+pub fn get_impl_id() -> SbiRet {
+    sbi_call(EXT_BASE, BASE_GET_IMPL_ID, 0, 0, 0, 0, 0)
+}
+
+pub fn get_impl_version() -> SbiRet {
+    sbi_call(EXT_BASE, BASE_GET_IMPL_VERSION, 0, 0, 0, 0, 0)
+}
+
+pub fn probe_extension(eid: usize) -> bool {
+    let ret = sbi_call(EXT_BASE, BASE_PROBE_EXTENSION, eid, 0, 0, 0, 0);
+    ret.is_ok() && ret.value != 0
+}
+
+// 控制台，只有旧版调用
+
 pub fn console_putchar(c: usize) {
-    sbi_call(SBI_CONSOLE_PUTCHAR, c, 0, 0);
+    sbi_legacy_call(SBI_CONSOLE_PUTCHAR, c, 0, 0);
 }
 
 pub fn console_getchar() -> usize {
-    sbi_call(SBI_CONSOLE_GETCHAR, 0, 0, 0)
+    sbi_legacy_call(SBI_CONSOLE_GETCHAR, 0, 0, 0)
+}
+
+// TIME 扩展
+
+pub fn set_timer(timer: usize) {
+    if has_extension(HAS_TIME) {
+        sbi_call(EXT_TIME, 0, timer, 0, 0, 0, 0);
+    } else {
+        sbi_legacy_call(SBI_SET_TIMER, timer, 0, 0);
+    }
+}
+
+/// 旧版调用使用从 0 号核开始的位图，hart_mask_base 为 usize::MAX 时表示所有核。
+/// 位图放不下的核返回 InvalidParam
+fn legacy_hart_mask(hart_mask: usize, hart_mask_base: usize) -> Result<usize, SbiRet> {
+    if hart_mask_base == usize::MAX {
+        return Ok(usize::MAX);
+    }
+    u32::try_from(hart_mask_base)
+        .ok()
+        .and_then(|base| hart_mask.checked_shl(base))
+        .filter(|&mask| mask >> hart_mask_base == hart_mask)
+        .ok_or(SbiRet::error(SBI_ERR_INVALID_PARAM))
+}
+
+// IPI 扩展
+
+/// 向 hart_mask 中的核发送软件中断，hart_mask 的第 i 位对应 hart_mask_base + i 号核
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiRet {
+    if has_extension(HAS_IPI) {
+        return sbi_call(EXT_IPI, 0, hart_mask, hart_mask_base, 0, 0, 0);
+    }
+    match legacy_hart_mask(hart_mask, hart_mask_base) {
+        Ok(mask) => SbiRet::legacy(sbi_legacy_call(
+            SBI_SEND_IPI,
+            &mask as *const _ as usize,
+            0,
+            0,
+        )),
+        Err(ret) => ret,
+    }
+}
+
+// RFENCE 扩展
+
+pub fn remote_fence_i(hart_mask: usize, hart_mask_base: usize) -> SbiRet {
+    if has_extension(HAS_RFENCE) {
+        return sbi_call(
+            EXT_RFENCE,
+            RFENCE_FENCE_I,
+            hart_mask,
+            hart_mask_base,
+            0,
+            0,
+            0,
+        );
+    }
+    match legacy_hart_mask(hart_mask, hart_mask_base) {
+        Ok(mask) => SbiRet::legacy(sbi_legacy_call(
+            SBI_REMOTE_FENCE_I,
+            &mask as *const _ as usize,
+            0,
+            0,
+        )),
+        Err(ret) => ret,
+    }
+}
+
+/// 刷新 hart_mask 中的核在 [start, start + size) 上的 TLB
+pub fn remote_sfence_vma(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+) -> SbiRet {
+    if has_extension(HAS_RFENCE) {
+        return sbi_call(
+            EXT_RFENCE,
+            RFENCE_SFENCE_VMA,
+            hart_mask,
+            hart_mask_base,
+            start,
+            size,
+            0,
+        );
+    }
+    match legacy_hart_mask(hart_mask, hart_mask_base) {
+        Ok(mask) => SbiRet::legacy(sbi_legacy_call(
+            SBI_REMOTE_SFENCE_VMA,
+            &mask as *const _ as usize,
+            start,
+            size,
+        )),
+        Err(ret) => ret,
+    }
+}
+
+pub fn remote_sfence_vma_asid(
+    hart_mask: usize,
+    hart_mask_base: usize,
+    start: usize,
+    size: usize,
+    asid: usize,
+) -> SbiRet {
+    if has_extension(HAS_RFENCE) {
+        sbi_call(
+            EXT_RFENCE,
+            RFENCE_SFENCE_VMA_ASID,
+            hart_mask,
+            hart_mask_base,
+            start,
+            size,
+            asid,
+        )
+    } else {
+        // 退化为不区分 asid 的刷新
+        remote_sfence_vma(hart_mask, hart_mask_base, start, size)
+    }
+}
+
+// HSM 扩展，没有对应的旧版调用
+
+/// 唤醒 hartid 对应的核，从物理地址 start_addr 开始执行，a0 = hartid，a1 = opaque
+pub fn hart_start(hartid: usize, start_addr: usize, opaque: usize) -> SbiRet {
+    if !has_extension(HAS_HSM) {
+        return SbiRet::error(SBI_ERR_NOT_SUPPORTED);
+    }
+    sbi_call(EXT_HSM, HSM_HART_START, hartid, start_addr, opaque, 0, 0)
+}
+
+/// 停止当前核，成功时不会返回
+pub fn hart_stop() -> SbiRet {
+    if !has_extension(HAS_HSM) {
+        return SbiRet::error(SBI_ERR_NOT_SUPPORTED);
+    }
+    sbi_call(EXT_HSM, HSM_HART_STOP, 0, 0, 0, 0, 0)
+}
+
+pub fn hart_get_status(hartid: usize) -> Result<HartStatus, SbiError> {
+    if !has_extension(HAS_HSM) {
+        return Err(SbiError::NotSupported);
+    }
+    sbi_call(EXT_HSM, HSM_HART_GET_STATUS, hartid, 0, 0, 0, 0)
+        .into_result()
+        .map(HartStatus::from)
+}
+
+// SRST 扩展
+
+pub fn system_reset(reset_type: usize, reset_reason: usize) -> SbiRet {
+    if !has_extension(HAS_SRST) {
+        return SbiRet::error(SBI_ERR_NOT_SUPPORTED);
+    }
+    sbi_call(EXT_SRST, 0, reset_type, reset_reason, 0, 0, 0)
 }
 
 pub fn shutdown() -> ! {
-    sbi_call(SBI_SHUTDOWN, 0, 0, 0);
+    if has_extension(HAS_SRST) {
+        system_reset(RESET_TYPE_SHUTDOWN, RESET_REASON_NO_REASON);
+    }
+    sbi_legacy_call(SBI_SHUTDOWN, 0, 0, 0);
     panic!("It should shutdown!");
 }
-
-pub fn set_timer(timer:usize) {
-    sbi_call(SBI_SET_TIMER, timer,0, 0);
-}
\ No newline at end of file
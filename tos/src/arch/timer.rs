@@ -1,6 +1,8 @@
 //TODO 完善时钟中断 双核
 use super::sbi::set_timer;
-use crate::arch::config::CLOCK_FREQ;
+use crate::arch::config::{BOOT_CPU_ID, CLOCK_FREQ, CPU_NUM};
+use crate::arch::cpu::get_cpu_id;
+use crate::kernel::timer as kernel_timer;
 use core::time::Duration;
 use riscv::register::{sie, time};
 
@@ -15,6 +17,9 @@ const NSEC_PER_SEC: u64 = 1_000_000_000;
 // 时钟中断间隔
 pub const INTERVAL: u64 = CLOCK_FREQ / TICKS_PER_SEC - 1;
 
+/// 各核下一次周期时钟中断的时间
+static mut NEXT_TICK: [u64; CPU_NUM] = [0; CPU_NUM];
+
 // 初始化时钟中断
 pub fn init() {
     unsafe {
@@ -27,17 +32,124 @@ pub fn init() {
     set_next_timeout();
 }
 
+/// 时钟中断，周期时钟或内核定时器到期，在完整的 `__trap` 中调用
 pub fn tick() {
-    set_next_timeout();
+    let now = read_time();
+    account_ticks(now);
+    report_ticks();
+    kernel_timer::expire(now);
+    program_next_event(kernel_timer::next_deadline());
+}
+
+/// 时钟中断的快速路径，此时没有保存 sepc/sstatus，不能执行可能再次陷入的代码。
+/// 只补上时钟计数并设置下一次周期时钟；有到期的定时器或需要输出时触发 S 态软件中断，
+/// 在完整的 `__trap` 中由 `tick` 处理
+pub fn tick_fast() {
+    let now = read_time();
+    account_ticks(now);
+    let deadline = kernel_timer::next_deadline();
+    if deadline.map_or(false, |deadline| deadline <= now) {
+        raise_soft_interrupt();
+        program_next_event(None);
+        return;
+    }
+    if report_pending() {
+        raise_soft_interrupt();
+    }
+    program_next_event(deadline);
+}
+
+/// 置位 sip.SSIP，开中断后进入 `__trap`
+fn raise_soft_interrupt() {
+    unsafe { asm!("csrs sip, {0}", in(reg) 1 << 1) };
+}
+
+/// 清除 sip.SSIP
+pub fn clear_soft_interrupt() {
+    unsafe { asm!("csrc sip, {0}", in(reg) 1 << 1) };
+}
+
+/// 补上到 `now` 为止错过的周期时钟，关中断或空闲时可能错过多个
+fn account_ticks(now: u64) {
+    let cpu_id = get_cpu_id();
+    let next_tick = unsafe { &mut NEXT_TICK[cpu_id] };
+    if now < *next_tick {
+        return;
+    }
+    let missed = (now - *next_tick) / INTERVAL + 1;
+    *next_tick += missed * INTERVAL;
+    // 每个核都有时钟中断，只由主核计数
+    if cpu_id != BOOT_CPU_ID {
+        return;
+    }
+    unsafe { TICKS += missed };
+}
+
+/// 主核上次输出时的时钟计数
+static mut REPORTED_TICKS: u64 = 0;
+
+/// 主核的时钟计数经过了整秒，需要输出
+fn report_pending() -> bool {
+    get_cpu_id() == BOOT_CPU_ID
+        && unsafe { TICKS / TICKS_PER_SEC != REPORTED_TICKS / TICKS_PER_SEC }
+}
+
+/// 每秒输出一次时钟计数，开启 trap-stats 时每 10 秒输出一次统计。会输出日志，不能在快速路径中调用
+fn report_ticks() {
+    if !report_pending() {
+        return;
+    }
     unsafe {
-        TICKS += 1;
-        if TICKS % TICKS_PER_SEC == 0 {
-            println!("{} s", TICKS / TICKS_PER_SEC);
+        trace!("{} s", TICKS / TICKS_PER_SEC);
+        #[cfg(feature = "trap-stats")]
+        if TICKS / (TICKS_PER_SEC * 10) != REPORTED_TICKS / (TICKS_PER_SEC * 10) {
+            super::trap_stats::print();
         }
+        REPORTED_TICKS = TICKS;
     }
 }
 
+/// 空闲时停止周期时钟，只在本核最近的定时器到期时唤醒
+pub fn stop_tick(deadline: Option<u64>) {
+    set_timer(deadline.unwrap_or(u64::MAX) as usize);
+}
+
+/// 从空闲中醒来，补上期间的时钟计数并恢复周期时钟
+pub fn restart_tick() {
+    account_ticks(read_time());
+    report_ticks();
+    program_next_event(kernel_timer::next_deadline());
+}
+
+/// 主核上的时钟中断计数
+pub fn get_ticks() -> u64 {
+    unsafe { TICKS }
+}
+
+/// 当前的 time 寄存器
+#[inline]
+pub fn read_time() -> u64 {
+    time::read64()
+}
+
+/// 将时长转换为 time 寄存器的计数
+pub fn duration_to_time(duration: Duration) -> u64 {
+    (duration.as_nanos() * CLOCK_FREQ as u128 / NSEC_PER_SEC as u128) as u64
+}
+
+/// 将 time 寄存器的计数转换为时长
+pub fn time_to_duration(time: u64) -> Duration {
+    Duration::from_nanos((time as u128 * NSEC_PER_SEC as u128 / CLOCK_FREQ as u128) as u64)
+}
+
+/// 将 SBI 定时器设置为下一次周期时钟与 `deadline` 中较早的一个
+pub fn program_next_event(deadline: Option<u64>) {
+    let next_tick = unsafe { NEXT_TICK[get_cpu_id()] };
+    set_timer(deadline.map_or(next_tick, |deadline| deadline.min(next_tick)) as usize);
+}
+
 #[inline]
 pub fn set_next_timeout() {
-    set_timer(time::read() + INTERVAL as usize);
+    unsafe { NEXT_TICK[get_cpu_id()] = read_time() + INTERVAL };
+    program_next_event(kernel_timer::next_deadline());
 }
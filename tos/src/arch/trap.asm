@@ -1,7 +1,18 @@
 # 我们将会用一个宏来用循环保存寄存器。这是必要的设置
 .altmacro
 .set    REG_SIZE, 8             # 寄存器宽度对应的字节数 64bits 对应8bytes
-.set    TRAP_FRAME_SIZE, 34     # TrapFrame 的大小
+.set    TRAP_FRAME_SIZE, {TRAP_FRAME_SIZE}     # TrapFrame 的大小，由 trap.rs 根据 TrapFrameImpl 定义
+# 以下与 config.rs 一致
+.set    KERNEL_STACK_AREA_BITS, 30      # 内核线程栈位于最高的 1G 中
+.set    KERNEL_STACK_ALIGN_BITS, 14     # 每个内核栈占用 1 << 14，其中栈之下的部分为保护页
+.set    KERNEL_STACK_SIZE_BITS, 13      # 内核栈大小为 1 << 13
+.set    EMERGENCY_STACK_BITS, 14        # 每个核的紧急中断栈大小
+.set    CPU_NUM, {CPU_NUM}
+.set    FAST_FRAME_SIZE, 16     # 快速路径只保存调用者保存的寄存器
+# 为 1 时用户与内核使用不同的页表，进出用户态时交换 satp 与 TrapFrame 中的 satp
+.set    SEPARATE_PAGE_TABLE, {SEPARATE_PAGE_TABLE}
+# 为 1 时记录进入中断时的 cycle，用于统计各路径的开销
+.set    TRAP_STATS, {TRAP_STATS}
 
 # 宏：将寄存器存到栈上
 .macro SAVE_GP n
@@ -11,16 +22,111 @@
 .macro LOAD_GP n
     ld x\n, \n*8(sp)
 .endm
-.macro RESTORE_SYS_GP
+# 宏：读取跳板页中保存的地址。代码在 TRAMPOLINE 处执行，不能直接用 pc 相对寻址访问跳板页以外的符号，
+# 而跳板页内部的相对位置与链接时相同
+.macro LOAD_ADDR reg, literal
     .option push
     .option norelax
     1:
-        auipc gp, %pcrel_hi(__global_pointer$)
-        addi  gp, gp, %pcrel_lo(1b)
+        auipc \reg, %pcrel_hi(\literal)
+        ld    \reg, %pcrel_lo(1b)(\reg)
     .option pop
 .endm
+.macro RESTORE_SYS_GP
+    LOAD_ADDR gp, .global_pointer
+.endm
+# 宏：S->S 时检查内核线程栈是否还能放下 TrapFrame，放不下时跳转到 .stack_overflow
+# 进入时 sscratch 与 sp 相同，可以暂存 t0，结束时 t0 不变，sscratch 仍与 sp 相同
+.macro CHECK_KERNEL_STACK
+    csrw    sscratch, t0
+    srai    t0, sp, KERNEL_STACK_AREA_BITS
+    addi    t0, t0, 1
+    bnez    t0, 2f              # 不在内核线程栈区域（如启动栈）
+    # TrapFrame 的最低地址落在保护页中时，加上栈大小后 [KERNEL_STACK_SIZE_BITS, KERNEL_STACK_ALIGN_BITS) 位不全为 0
+    li      t0, (1 << KERNEL_STACK_SIZE_BITS) - TRAP_FRAME_SIZE * REG_SIZE
+    add     t0, t0, sp
+    slli    t0, t0, 64 - KERNEL_STACK_ALIGN_BITS
+    srli    t0, t0, 64 - KERNEL_STACK_ALIGN_BITS + KERNEL_STACK_SIZE_BITS
+    bnez    t0, .stack_overflow
+2:
+    csrrw   t0, sscratch, sp    # 恢复 t0，sscratch 重新保存 sp
+.endm
+# 宏：快速路径中保存/恢复调用者保存的寄存器，a0 最先保存，用于记录 cycle
+.macro FAST_REGS op
+    \op     ra, 1*8(sp)
+    \op     t0, 2*8(sp)
+    \op     t1, 3*8(sp)
+    \op     t2, 4*8(sp)
+    \op     a1, 5*8(sp)
+    \op     a2, 6*8(sp)
+    \op     a3, 7*8(sp)
+    \op     a4, 8*8(sp)
+    \op     a5, 9*8(sp)
+    \op     a6, 10*8(sp)
+    \op     a7, 11*8(sp)
+    \op     t3, 12*8(sp)
+    \op     t4, 13*8(sp)
+    \op     t5, 14*8(sp)
+    \op     t6, 15*8(sp)
+.endm
+# 宏：交换 satp 与 TrapFrame 中的 satp
+.macro SWAP_SATP
+    ld      t0, 34*8(sp)
+    csrrw   t0, satp, t0
+    sfence.vma
+    sd      t0, 34*8(sp)
+.endm
+
+# __trap 与 __restore 位于跳板页，映射在每个地址空间的 TRAMPOLINE 处，stvec 指向映射后的地址
+    .section .text.trampoline, "ax"
+
+# Vectored 模式的中断向量表，异常跳转到第 0 项，中断跳转到第 scause 项
+# 每项必须是 4 字节的跳转指令
+    .globl __trap_vector
+    .align 8
+__trap_vector:
+    .option push
+    .option norvc
+    j       __trap              # 0: 异常与系统调用
+    j       __trap              # 1: S 态软件中断
+    j       __trap
+    j       __trap
+    j       __trap
+    j       __trap_timer        # 5: S 态时钟中断
+    j       __trap
+    j       __trap
+    j       __trap
+    j       __trap              # 9: S 态外部中断
+    .option pop
+
+# S 态时钟中断的快速路径，只保存调用者保存的寄存器，然后调用 handle_timer_fast
+# 来自用户态时转入完整的 __trap，以便之后在其中进行调度
+__trap_timer:
+    csrrw   sp, sscratch, sp
+    bnez    sp, .timer_from_user
+    csrr    sp, sscratch
+    CHECK_KERNEL_STACK
+    csrw    sscratch, x0        # S 态中 sscratch 为 0
+
+    addi    sp, sp, -FAST_FRAME_SIZE * REG_SIZE
+    sd      a0, 0*8(sp)
+.if TRAP_STATS
+    rdcycle a0                  # handle_timer_fast 的参数：进入中断时的 cycle
+.endif
+    FAST_REGS sd
+
+    LOAD_ADDR t0, .handle_timer_fast
+    jalr    t0
+
+    FAST_REGS ld
+    ld      a0, 0*8(sp)
+    addi    sp, sp, FAST_FRAME_SIZE * REG_SIZE
+    sret
+
+.timer_from_user:
+    csrrw   sp, sscratch, sp    # 还原 sp 与 sscratch
+    j       __trap
 
-    .section .text
     .globl __trap
 # 进入中断
 # U->S
@@ -33,9 +139,30 @@ __trap:
     bnez    sp, .save_context
     csrr    sp, sscratch        # sp（如果是 S->S）
 
+    CHECK_KERNEL_STACK
+    j       .save_context
+
+# 内核栈溢出，切换到本核的紧急中断栈，由 handle_trap 报告
+.stack_overflow:
+    # t0 = emergency_stack + (hartid + 1) << EMERGENCY_STACK_BITS
+    LOAD_ADDR t0, .emergency_stack_base
+    slli    tp, tp, EMERGENCY_STACK_BITS
+    add     t0, t0, tp
+    srli    tp, tp, EMERGENCY_STACK_BITS
+    sd      sp, -8(t0)          # 暂存溢出的 sp
+    mv      sp, t0
+    ld      t0, -8(sp)
+    csrrw   t0, sscratch, t0    # 恢复 t0，sscratch 保存溢出的 sp
+
     # 此时 sp 指向内核栈
 .save_context:
     addi    sp, sp, -TRAP_FRAME_SIZE * REG_SIZE    # 在内核栈开辟 TrapFrame 的空间
+.if TRAP_STATS
+    sd      t0, 5*8(sp)
+    rdcycle t0
+    sd      t0, 35*8(sp)        # 进入中断时的 cycle
+    ld      t0, 5*8(sp)
+.endif
 
     # SAVE_GP 0             # x0 不用保存，因为它固定为 0
     SAVE_GP 1               # 保存 x1 寄存器
@@ -52,6 +179,14 @@ __trap:
     sd      t1, 33*8(sp)        # 保存 sepc
     sd      t2, 02*8(sp)        # 保存 x2(sp)
 
+.if SEPARATE_PAGE_TABLE
+    # U->S 时切换到内核页表。此前的保存写入当前线程的内核栈，创建用户线程时须将它映射到用户页表中
+    andi    t0, t0, 0x100       # SPP 位
+    bnez    t0, 1f
+    SWAP_SATP
+1:
+.endif
+
     RESTORE_SYS_GP          # 恢复给内核代码使用的 x3(gp) 寄存器
 
 # 因为可能出现中断嵌套，所以 sstatus sepc 也需要保存，scause stval 与特权级的切换并没有关系。
@@ -60,7 +195,8 @@ __trap:
     mv      a0, sp          # trap_frame: &mut TrapFrameImpl
     csrr    a1, scause      # scause: Scause
     csrr    a2, stval       # stval: usize
-    jal     handle_trap
+    LOAD_ADDR t0, .handle_trap
+    jalr    t0              # 不能用 jal，跳板页与 handle_trap 的距离和链接时不同
 
 
 
@@ -69,7 +205,8 @@ __trap:
 __restore:
     # 如果 S->U，则 sscratch == sp + TRAP_FRAME_SIZE * REG_SIZE
     # 如果 S->S，则 sscratch == 0（无需修改）
-    # S->U 有一种情况是 直接调用 __restore，此时 SPP 位应设置为 0
+    # S->U 有一种情况是 直接调用 __restore，此时 SPP 位应设置为 0，
+    # 并且需要调用 TRAMPOLINE 中的 __restore（见 trap::trampoline_va）
 
     csrr    t0, sstatus     # 读取 sstatus 寄存器
     andi    t0, t0, 0x100   # 将 SPP 位的值读取到 t0
@@ -77,6 +214,10 @@ __restore:
     bnez    t0, .restore_context
     addi    t0, sp, TRAP_FRAME_SIZE * REG_SIZE      # 获取内核栈顶地址
     csrw    sscratch, t0                            # 写入 sscratch
+.if SEPARATE_PAGE_TABLE
+    # S->U 时切换到用户页表，TrapFrame 中随后保存内核页表
+    SWAP_SATP
+.endif
 .restore_context:
     ld      t0, 32*8(sp)    # 读取 Contex 中的 sstatus 寄存器
     ld      t1, 33*8(sp)    # 读取 Contex 中的 sepc 寄存器
@@ -94,6 +235,24 @@ __restore:
 
     sret
 
+# 跳板页中用到的跳板页以外的地址
+    .align 3
+.global_pointer:
+    .quad __global_pointer$
+.handle_trap:
+    .quad handle_trap
+.handle_timer_fast:
+    .quad handle_timer_fast
+.emergency_stack_base:
+    .quad emergency_stack + (1 << EMERGENCY_STACK_BITS)
+
+# 每个核的紧急中断栈，内核栈溢出时使用
+    .section .bss.stack
+    .align 12
+    .globl emergency_stack
+emergency_stack:
+    .zero (1 << EMERGENCY_STACK_BITS) * CPU_NUM
+
 # 我们将会用一个宏来用循环保存寄存器。这是必要的设置
 // .altmacro
 // .set    REG_SIZE, 8             # 寄存器宽度对应的字节数
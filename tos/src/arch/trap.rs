@@ -79,6 +79,7 @@
 // }
 
 use core::intrinsics::transmute;
+use core::mem::size_of;
 #[macro_use]
 use riscv::register::{
     scause::{Exception, Interrupt, Scause, Trap},
@@ -87,41 +88,137 @@ use riscv::register::{
     stval, stvec,
 };
 
+use super::config::{
+    CPU_NUM, KERNEL_STACK_ALIGN_BITS, KERNEL_STACK_ALIGN_SIZE, KERNEL_STACK_AREA_START,
+    KERNEL_STACK_SIZE, KERNEL_STACK_TOP, TRAMPOLINE,
+};
+use super::cpu::get_cpu_id;
+use super::fp;
 use super::timer;
+#[cfg(feature = "trap-stats")]
+use super::trap_stats::{self, TrapPath};
+use crate::arch::interface::{Trap as TrapInterface, TrapFrame};
 use crate::arch::trap_context::TrapFrameImpl;
+use crate::kernel::irq;
+use crate::kernel::mm::address::VA;
+use crate::kernel::mm::space::{Access, PageFaultError};
+use crate::kernel::process::process::SIGSEGV;
+use crate::kernel::process::processor::{current_process, exit_current};
+use crate::ksyms::Symbol;
 
-global_asm!(include_str!("./trap.asm"));
+global_asm!(
+    include_str!("./trap.asm"),
+    TRAP_FRAME_SIZE = const size_of::<TrapFrameImpl>() / size_of::<usize>(),
+    CPU_NUM = const CPU_NUM,
+    SEPARATE_PAGE_TABLE = const cfg!(feature = "separate-page-table") as usize,
+    TRAP_STATS = const cfg!(feature = "trap-stats") as usize,
+);
+// __trap 中 sp 需要保持 16 字节对齐
+const _: () = assert!(size_of::<TrapFrameImpl>() % 16 == 0);
 extern "C" {
     pub fn __trap();
+    pub fn __trap_vector();
     pub fn __restore();
+    fn strampoline();
+}
+
+/// 跳板页中的符号在 TRAMPOLINE 处的地址，`__trap`/`__restore` 须通过该地址使用
+pub fn trampoline_va(symbol: usize) -> usize {
+    symbol - strampoline as usize + TRAMPOLINE
 }
 
 pub struct TrapImpl;
 
-impl crate::arch::trap_context::Trap for TrapImpl {
+/// 各核正在处理的中断现场，用于 panic 时打印
+static mut CURRENT_TRAP_FRAME: [usize; CPU_NUM] = [0; CPU_NUM];
+
+/// 当前核正在处理的中断现场，不在 `handle_trap` 中时返回 None
+pub fn current_trap_frame() -> Option<&'static TrapFrameImpl> {
+    let trap_frame = unsafe { CURRENT_TRAP_FRAME.get(get_cpu_id()).copied()? };
+    unsafe { (trap_frame as *const TrapFrameImpl).as_ref() }
+}
+
+/// 在 `handle_trap` 期间记录当前的中断现场，返回时恢复为外层中断的现场
+struct TrapFrameRecord {
+    previous: usize,
+}
+
+impl TrapFrameRecord {
+    fn new(trap_frame: &TrapFrameImpl) -> Self {
+        let current = unsafe { &mut CURRENT_TRAP_FRAME[get_cpu_id()] };
+        let previous = *current;
+        *current = trap_frame as *const _ as usize;
+        Self { previous }
+    }
+}
+
+impl Drop for TrapFrameRecord {
+    fn drop(&mut self) {
+        unsafe { CURRENT_TRAP_FRAME[get_cpu_id()] = self.previous };
+    }
+}
+
+impl TrapInterface for TrapImpl {
     fn init() {
         unsafe {
-            // 使用 Direct 模式，将中断入口设置为 `__interrupt`
             sscratch::write(0);
-            stvec::write(__trap as usize, stvec::TrapMode::Direct);
+            // 使用 Vectored 模式，时钟中断进入快速路径
+            stvec::write(
+                trampoline_va(__trap_vector as usize),
+                stvec::TrapMode::Vectored,
+            );
+            // 不支持 Vectored 模式时（如 k210 遵循的 1.9.1 版规范），所有中断都进入 `__trap`
+            if stvec::read().trap_mode() != Some(stvec::TrapMode::Vectored) {
+                stvec::write(trampoline_va(__trap as usize), stvec::TrapMode::Direct);
+            }
 
-            // // 开启 S 态外部中断
-            // sie::set_sext();
-            // // 开启 S 态软件中断
-            // sie::set_ssoft();
+            // 开启 S 态外部中断，PLIC 中的设备中断在注册处理函数时才打开
+            sie::set_sext();
+            // 开启 S 态软件中断，时钟中断的快速路径通过它执行到期的定时器
+            sie::set_ssoft();
             sstatus::set_sie();
         }
 
         // XXX
         timer::init();
 
-        println!("mod trap initialized");
+        debug!("mod trap initialized");
+    }
+}
+
+/// `addr` 位于某个内核线程栈之下的保护页中时，返回该线程的线程号
+fn guard_page_owner(addr: usize) -> Option<usize> {
+    if addr < KERNEL_STACK_AREA_START || addr >= KERNEL_STACK_TOP {
+        return None;
+    }
+    if addr & (KERNEL_STACK_ALIGN_SIZE - 1) >= KERNEL_STACK_ALIGN_SIZE - KERNEL_STACK_SIZE {
+        return None;
     }
+    Some((KERNEL_STACK_TOP - 1 - addr) >> KERNEL_STACK_ALIGN_BITS)
+}
+
+/// S 态时钟中断的快速路径，由 `__trap_timer` 调用。
+/// 此时只保存了调用者保存的寄存器，处理过程中不能开启中断
+#[no_mangle]
+#[cfg_attr(not(feature = "trap-stats"), allow(unused_variables))]
+extern "C" fn handle_timer_fast(entry_cycle: usize) {
+    timer::tick_fast();
+    #[cfg(feature = "trap-stats")]
+    trap_stats::record(TrapPath::FastTimer, entry_cycle);
 }
 
 /// 中断处理入口
 #[no_mangle]
 pub fn handle_trap(trap_frame: &mut TrapFrameImpl, scause: Scause, stval: usize) {
+    let _record = TrapFrameRecord::new(trap_frame);
+    #[cfg(feature = "trap-stats")]
+    let _stats = trap_stats::Record::new(TrapPath::from_cause(scause.cause()), trap_frame.cycle);
+    // 内核栈放不下 TrapFrame 时，__trap 会切换到紧急中断栈
+    if trap_frame.sstatus.spp() == SPP::Supervisor {
+        if let Some(tid) = guard_page_owner(trap_frame.sp() - size_of::<TrapFrameImpl>()) {
+            panic!("kernel stack overflow in thread {}", tid);
+        }
+    }
     debug_assert_eq!(
         unsafe { transmute::<_, usize>(trap_frame.sstatus) },
         unsafe { transmute::<_, usize>(sstatus::read()) }
@@ -153,10 +250,32 @@ pub fn handle_trap(trap_frame: &mut TrapFrameImpl, scause: Scause, stval: usize)
             // }
             return;
         }
+        // 快速路径转来的定时器到期，在关中断的情况下执行回调
+        Trap::Interrupt(Interrupt::SupervisorSoft) => {
+            timer::clear_soft_interrupt();
+            timer::tick();
+            return;
+        }
+        // 外部中断，在关中断的情况下分发给设备驱动
+        Trap::Interrupt(Interrupt::SupervisorExternal) => {
+            irq::handle_external();
+            return;
+        }
+        // 用户线程换入后第一次使用浮点指令，恢复浮点寄存器后重新执行该指令
+        Trap::Exception(Exception::IllegalInstruction)
+            if trap_frame.sstatus.spp() == SPP::User && fp::handle_fp_trap(trap_frame) =>
+        {
+            return;
+        }
         _ => {}
     }
 
-    println!("来自 {:?} 态的 trap", trap_frame.sstatus.spp());
+    trace!("来自 {:?} 态的 trap", trap_frame.sstatus.spp());
+
+    // 开中断后可能切换到其他线程，先保存用户线程修改过的浮点寄存器
+    if trap_frame.sstatus.spp() == SPP::User {
+        trap_frame.switch_out_fp();
+    }
 
     unsafe {
         // 开启 SIE（不是 sie 寄存器），全局中断使能，允许内核态被中断打断
@@ -166,47 +285,74 @@ pub fn handle_trap(trap_frame: &mut TrapFrameImpl, scause: Scause, stval: usize)
     match scause.cause() {
         // 来自用户态的系统调用
         // Trap::Exception(Exception::UserEnvCall) => syscall_handler(),
-        // 外部中断
-        Trap::Interrupt(Interrupt::SupervisorExternal) => unimplemented!(),
         // 缺页异常
         Trap::Exception(Exception::LoadPageFault)
         | Trap::Exception(Exception::StorePageFault)
         | Trap::Exception(Exception::InstructionPageFault)
         | Trap::Exception(Exception::LoadFault)
         | Trap::Exception(Exception::StoreFault) => {
-            println!(
-                "cause: {:?}, stval: {:x}, sepc: {:x}",
+            if trap_frame.sstatus.spp() == SPP::Supervisor {
+                if let Some(tid) = guard_page_owner(stval) {
+                    panic!("kernel stack overflow in thread {}", tid);
+                }
+            }
+            debug!(
+                "cause: {:?}, stval: {:x}, sepc: {}",
                 scause.cause(),
                 stval::read(),
-                trap_frame.sepc,
+                Symbol(trap_frame.sepc),
                 // RegisterImpl::sp()
             );
-            handle_pagefault(stval::read());
+            if let Err(error) = handle_pagefault(trap_frame, scause.cause(), stval) {
+                // 用户态的非法访问发送给进程，内核态的非法访问无法恢复
+                if trap_frame.sstatus.spp() == SPP::Supervisor {
+                    panic!(
+                        "cause: {:?}, stval: {:x}, sepc: {}, {:?}",
+                        scause.cause(),
+                        stval,
+                        Symbol(trap_frame.sepc),
+                        error
+                    );
+                }
+                warn!(
+                    "user {:?} at {:#x}, sepc: {:#x}, {:?}",
+                    scause.cause(),
+                    stval,
+                    trap_frame.sepc,
+                    error
+                );
+                current_process().send_signal(SIGSEGV);
+                // 还没有信号处理，按 SIGSEGV 的默认动作结束进程，不能返回到触发异常的指令
+                drop(_record);
+                #[cfg(feature = "trap-stats")]
+                drop(_stats);
+                exit_current(128 + SIGSEGV as i32);
+            }
         }
         Trap::Exception(Exception::InstructionFault) => {
             #[cfg(feature = "k210")]
             panic!(
                 "cause: Instruction access fault
-            , stval: {:x}, sepc: {:x}",
+            , stval: {:x}, sepc: {}",
                 stval,
-                sepc::read()
+                Symbol(sepc::read())
             );
 
             #[cfg(not(feature = "k210"))]
             panic!(
-                "cause: {:?}, stval: {:x}, sepc: {:x}",
+                "cause: {:?}, stval: {:x}, sepc: {}",
                 scause.cause(),
                 stval::read(),
-                sepc::read()
+                Symbol(sepc::read())
             );
         }
         // 其他情况，无法处理
         _ => {
             panic!(
-                "cause: {:?}, stval: {:x}, sepc: {:x}",
+                "cause: {:?}, stval: {:x}, sepc: {}",
                 scause.cause(),
                 stval::read(),
-                sepc::read()
+                Symbol(sepc::read())
             );
         }
     }
@@ -215,6 +361,10 @@ pub fn handle_trap(trap_frame: &mut TrapFrameImpl, scause: Scause, stval: usize)
         // 返回时关闭全局中断
         riscv::register::sstatus::clear_sie();
     }
+    // 处理期间可能运行过其他线程，浮点寄存器中已不是该线程的状态时，推迟到它使用时恢复
+    if trap_frame.sstatus.spp() == SPP::User {
+        trap_frame.switch_in_fp();
+    }
     // println!("handle_interrupt end");
 }
 
@@ -242,4 +392,28 @@ pub fn handle_trap(trap_frame: &mut TrapFrameImpl, scause: Scause, stval: usize)
 //     };
 // }
 
-fn handle_pagefault(s: usize) {}
+/// 在当前进程的地址空间中处理缺页异常
+fn handle_pagefault(
+    trap_frame: &TrapFrameImpl,
+    cause: Trap,
+    stval: usize,
+) -> Result<(), PageFaultError> {
+    let access = match cause {
+        Trap::Exception(Exception::LoadPageFault) => Access::Read,
+        Trap::Exception(Exception::StorePageFault) => Access::Write,
+        Trap::Exception(Exception::InstructionPageFault) => Access::Execute,
+        // 访问错误（如 PMP 拒绝）无法通过映射解决
+        _ => return Err(PageFaultError::PermissionDenied),
+    };
+    let user = trap_frame.sstatus.spp() == SPP::User;
+    let process = current_process();
+    // 持有地址空间的锁时访问了用户内存，继续处理会死锁
+    if process.memory_set_held_by_current() {
+        panic!(
+            "page fault at {:#x} while holding the memory set, sepc: {}",
+            stval,
+            Symbol(trap_frame.sepc)
+        );
+    }
+    process.with_memory_set(|memory_set| memory_set.handle_page_fault(VA(stval), access, user))
+}
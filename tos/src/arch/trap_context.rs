@@ -1,6 +1,12 @@
+use core::fmt;
+use core::intrinsics::transmute;
 use core::mem::zeroed;
 
-use riscv::register::sstatus::{self, Sstatus, SPP::*};
+use super::fp::{self, FpState};
+use super::interface::TrapFrame;
+use crate::ksyms::Symbol;
+
+use riscv::register::sstatus::{self, Sstatus, FS, SPP::*};
 
 /// 发生中断时，保存的寄存器
 #[repr(C)]
@@ -13,6 +19,39 @@ pub struct TrapFrameImpl {
     pub sstatus: Sstatus,
     /// 保存中断地址的特权态寄存器
     pub sepc: usize,
+    /// 用户与内核使用不同页表时，`__trap`/`__restore` 在此交换 satp：
+    /// 用户态运行时为内核页表，内核态运行时为用户页表
+    pub satp: usize,
+    /// 进入中断时的 cycle，开启 `trap-stats` 特性时由 `__trap` 记录
+    pub cycle: usize,
+}
+
+/// 通用寄存器的 ABI 名称
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// 每行四个寄存器，用于 panic 时打印现场
+impl fmt::Display for TrapFrameImpl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, value) in self.x.iter().enumerate() {
+            write!(f, "{:>4}: {:#018x}", REGISTER_NAMES[i], value)?;
+            if i % 4 == 3 {
+                writeln!(f)?;
+            } else {
+                write!(f, "  ")?;
+            }
+        }
+        write!(
+            f,
+            "sepc: {}  sstatus: {:#x} ({:?})",
+            Symbol(self.sepc),
+            unsafe { transmute::<_, usize>(self.sstatus) },
+            self.sstatus.spp()
+        )
+    }
 }
 
 /// 创建一个用 0 初始化的 TrapFrameImpl
@@ -22,41 +61,6 @@ impl Default for TrapFrameImpl {
     }
 }
 
-pub trait TrapFrame: Default {
-    /// 获取栈指针
-    fn sp(&self) -> usize;
-
-    /// 设置栈指针
-    fn set_sp(&mut self, value: usize) -> &mut Self;
-
-    /// 获取返回地址
-    fn ra(&self) -> usize;
-
-    /// 设置返回地址
-    fn set_ra(&mut self, value: usize) -> &mut Self;
-
-    /// 设置返回值
-    fn set_return_value(&mut self, value: usize) -> &mut Self;
-
-    /// 设置入口
-    fn set_entry_point(&mut self, value: usize) -> &mut Self;
-
-    /// 按照函数调用规则写入参数
-    fn set_arguments(&mut self, arguments: &[usize]) -> &mut Self;
-
-    /// 为线程构建初始 `TrapFrameImpl`
-    fn init(
-        &mut self,
-        stack_top: usize,
-        entry_point: usize,
-        arguments: Option<&[usize]>,
-        is_user: bool,
-    );
-}
-
-pub trait Trap {
-    fn init();
-}
 impl TrapFrame for TrapFrameImpl {
     /// 获取栈指针
     fn sp(&self) -> usize {
@@ -99,6 +103,22 @@ impl TrapFrame for TrapFrameImpl {
         self
     }
 
+    /// 设置返回用户态时使用的页表
+    fn set_token(&mut self, token: usize) -> &mut Self {
+        self.satp = token;
+        self
+    }
+
+    /// 线程换出时调用，浮点寄存器被修改过时保存
+    fn switch_out_fp(&mut self) {
+        fp::switch_out(self);
+    }
+
+    /// 线程换入时调用，浮点寄存器在该线程再次使用时才恢复
+    fn switch_in_fp(&mut self) {
+        fp::switch_in(self);
+    }
+
     /// 为线程构建初始 `TrapFrameImpl`
     fn init(
         &mut self,
@@ -120,6 +140,11 @@ impl TrapFrame for TrapFrameImpl {
         // println!("entry_point: {:x}", entry_point);
         // 设置 sstatus
         self.sstatus = sstatus::read();
+        // 用户线程的浮点寄存器在第一次使用时从全 0 的 FpState 恢复
+        if is_user {
+            *fp::fp_state(self) = FpState::default();
+        }
+        fp::set_fs(&mut self.sstatus, FS::Off);
         // 中断前处于内核态还是用户态
         if is_user {
             unsafe{sstatus::set_spp(User);}
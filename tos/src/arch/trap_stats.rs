@@ -0,0 +1,109 @@
+//! 各中断处理路径的次数与开销，开启 `trap-stats` 特性时有效
+//!
+//! 开销为从开辟 TrapFrame 到处理函数返回的 cycle 数，不含恢复现场
+use core::sync::atomic::{AtomicUsize, Ordering};
+use riscv::register::cycle;
+use riscv::register::scause::{Interrupt, Trap};
+
+/// 中断处理的路径
+#[derive(Clone, Copy, Debug)]
+pub enum TrapPath {
+    /// 内核态的时钟中断，只保存调用者保存的寄存器
+    FastTimer,
+    /// 用户态的时钟中断，以及不支持 Vectored 模式时的时钟中断
+    Timer,
+    Software,
+    External,
+    /// 异常与系统调用
+    Exception,
+}
+
+impl TrapPath {
+    /// `__trap` 中根据 scause 区分路径
+    pub fn from_cause(cause: Trap) -> Self {
+        match cause {
+            Trap::Interrupt(Interrupt::SupervisorTimer) => TrapPath::Timer,
+            Trap::Interrupt(Interrupt::SupervisorSoft) => TrapPath::Software,
+            Trap::Interrupt(_) => TrapPath::External,
+            Trap::Exception(_) => TrapPath::Exception,
+        }
+    }
+}
+
+const PATHS: [TrapPath; 5] = [
+    TrapPath::FastTimer,
+    TrapPath::Timer,
+    TrapPath::Software,
+    TrapPath::External,
+    TrapPath::Exception,
+];
+
+struct PathStats {
+    count: AtomicUsize,
+    cycles: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl PathStats {
+    const fn new() -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+            cycles: AtomicUsize::new(0),
+            max: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// 所有核共用，按 `TrapPath` 的顺序排列
+static STATS: [PathStats; 5] = [
+    PathStats::new(),
+    PathStats::new(),
+    PathStats::new(),
+    PathStats::new(),
+    PathStats::new(),
+];
+
+/// 记录一次中断，`entry_cycle` 为进入中断时的 cycle
+pub fn record(path: TrapPath, entry_cycle: usize) {
+    let cost = cycle::read().wrapping_sub(entry_cycle);
+    let stats = &STATS[path as usize];
+    stats.count.fetch_add(1, Ordering::Relaxed);
+    stats.cycles.fetch_add(cost, Ordering::Relaxed);
+    stats.max.fetch_max(cost, Ordering::Relaxed);
+}
+
+/// 在 `handle_trap` 返回时记录
+pub struct Record {
+    path: TrapPath,
+    entry_cycle: usize,
+}
+
+impl Record {
+    pub fn new(path: TrapPath, entry_cycle: usize) -> Self {
+        Self { path, entry_cycle }
+    }
+}
+
+impl Drop for Record {
+    fn drop(&mut self) {
+        record(self.path, self.entry_cycle);
+    }
+}
+
+/// 输出各路径的次数、平均与最大 cycle 数
+pub fn print() {
+    for path in PATHS {
+        let stats = &STATS[path as usize];
+        let count = stats.count.load(Ordering::Relaxed);
+        if count == 0 {
+            continue;
+        }
+        info!(
+            "trap {:?}: {} times, avg {} cycles, max {} cycles",
+            path,
+            count,
+            stats.cycles.load(Ordering::Relaxed) / count,
+            stats.max.load(Ordering::Relaxed)
+        );
+    }
+}
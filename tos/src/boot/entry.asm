@@ -1,46 +1,117 @@
+     .equ    KERNEL_MAP_OFFSET, 0xffffffc000000000   # 虚拟地址的偏移量
+     .equ    BOOT_STACK_SIZE, {BOOT_STACK_SIZE}      # 每个核的启动栈大小，由 main.rs 根据 config 定义
+     .equ    CPU_NUM, {CPU_NUM}
+     .equ    BOOT_CPU_ID, {BOOT_CPU_ID}
+     .equ    SBI_EXT_HSM, 0x48534D
+     .equ    SBI_HSM_HART_START, 0
+
+ # 宏：将页表 table 的最后一项指向 target 所在的物理页，此时尚未开启分页，使用 t2 t3
+ .macro SET_LAST_PTE table, target, flags
+     lla     t2, \target
+     srli    t2, t2, 12
+     slli    t2, t2, 10
+     ori     t2, t2, \flags
+     lla     t3, \table + 8 * 511
+     sd      t2, 0(t3)
+ .endm
+
      .section .text.entry
      .globl _start
+ # 主核入口，a0 = hartid，a1 = 设备树物理地址
  _start:
-     li t1, 0xffffffc000000000       # 虚拟地址的偏移量
- .A: # sp = boot_stack
+     li      t2, CPU_NUM
+     bltu    a0, t2, .A
+     # SBI 选择了编号超出 CPU_NUM 的核启动，没有它的启动栈：唤醒 BOOT_CPU_ID 后停机
+     mv      a2, a1                  # opaque = 设备树物理地址
+     lla     a1, _start
+     li      a0, BOOT_CPU_ID
+     li      a6, SBI_HSM_HART_START
+     li      a7, SBI_EXT_HSM
+     ecall
+     j       .park
+ .A: # t0 = rust_main
+     auipc   t0, %pcrel_hi(rust_main)
+     addi    t0, t0, %pcrel_lo(.A)   # 得到物理地址
+     j       .boot
+
+     .globl _start_secondary
+ # 从核入口，由主核通过 SBI HSM 的 hart_start 唤醒，a0 = hartid，a1 = opaque
+ _start_secondary:
+     # 编号超出 CPU_NUM 的核没有启动栈，停机
+     li      t2, CPU_NUM
+     bgeu    a0, t2, .park
+ .B: # t0 = rust_main_secondary
+     auipc   t0, %pcrel_hi(rust_main_secondary)
+     addi    t0, t0, %pcrel_lo(.B)   # 得到物理地址
+
+ .boot:
+     # tp 保存当前的 hartid
+     mv      tp, a0
+     li      t1, KERNEL_MAP_OFFSET
+
+ # 将跳板页映射到 TRAMPOLINE，使 stvec 在启动页表中同样有效
+ # boot_page_table[511] -> boot_trampoline_l1[511] -> boot_trampoline_l0[511] -> strampoline
+     SET_LAST_PTE boot_page_table, boot_trampoline_l1, 0x01     # V
+     SET_LAST_PTE boot_trampoline_l1, boot_trampoline_l0, 0x01
+     SET_LAST_PTE boot_trampoline_l0, strampoline, 0xcb         # VRXAD
+
+ .C: # 开启分页，satp = (8 << 60) | boot_page_table 的物理页号
+     auipc   t2, %pcrel_hi(boot_page_table)
+     addi    t2, t2, %pcrel_lo(.C)
+     srli    t2, t2, 12
+     li      t3, 8 << 60
+     or      t2, t2, t3
+     csrw    satp, t2
+     sfence.vma
+
+ .D: # sp = boot_stack + BOOT_STACK_SIZE * (hartid + 1)
      auipc   sp, %pcrel_hi(boot_stack)
-     addi    sp, sp, %pcrel_lo(.A)   # 得到物理地址
+     addi    sp, sp, %pcrel_lo(.D)   # 得到物理地址
      add     sp, sp, t1              # 得到虚拟地址
-     # sp = boot_stack + 0x1000 * a0+4
-     li      t2, 4096
-     addi    t3, a0, 2
+     li      t2, BOOT_STACK_SIZE
+     addi    t3, a0, 1
      mul     t2, t2, t3
-     c.add   sp, t2
+     add     sp, sp, t2
 
- .B: # 跳转至 rust_main
-     auipc   t0, %pcrel_hi(rust_main)
-     addi    t0, t0, %pcrel_lo(.B)   # 得到物理地址
+     # 跳转至 rust_main / rust_main_secondary
      add     t0, t0, t1              # 得到虚拟地址
      jr      t0
 
+ # 不使用的核，此时 sie 为 0，wfi 返回后继续等待
+ .park:
+     wfi
+     j       .park
 
 
- # 4K 大小的 boot_stack，放在 .bss，如果放在 .data 会占用空间
+
+ # 每个核 BOOT_STACK_SIZE 大小的 boot_stack，放在 .bss，如果放在 .data 会占用空间
      .section .bss.stack
      .align 12
      .globl boot_stack
  boot_stack:
-     .zero 4096 * 2
+     .zero BOOT_STACK_SIZE * CPU_NUM
      .globl boot_stack_top
  boot_stack_top:
 
+ # 启动时使用的页表，每项映射 1G 的大页
+     .section .data
+     .align 12
+ boot_page_table:
+     # 0x0000_0000_8000_0000 -> 0x8000_0000，跳转到高地址之前使用的恒等映射
+     .quad 0
+     .quad 0
+     .quad (0x80000 << 10) | 0xcf    # VRWXAD
+     .zero 8 * 253
+     # 0xffff_ffc0_0000_0000 -> 0x0000_0000，共 4G 的线性映射，包含 MMIO
+     .quad (0x00000 << 10) | 0xcf
+     .quad (0x40000 << 10) | 0xcf
+     .quad (0x80000 << 10) | 0xcf
+     .quad (0xc0000 << 10) | 0xcf
+     .zero 8 * 252
 
-#      .section .text.entry
-#      .globl _start
-# _start:
-
-#      la sp, boot_stack_top
-
-#      call rust_main
-
-#      .section .bss.stack
-#      .globl boot_stack
-# boot_stack:
-#      .space 4096 * 16
-#      .globl boot_stack_top
-# boot_stack_top:
\ No newline at end of file
+ # 启动时映射跳板页用到的次级页表
+     .align 12
+ boot_trampoline_l1:
+     .zero 4096
+ boot_trampoline_l0:
+     .zero 4096
@@ -1,12 +1,67 @@
-use crate::arch::sbi::console_putchar;
+//! 控制台
+//!
+//! 驱动初始化后使用第一个字符设备，输入由设备的接收中断放入缓冲区；
+//! 在此之前、没有字符设备或 panic 时通过 SBI 逐字节输出
+use crate::arch::sbi::{console_getchar, console_putchar};
+use crate::kernel::drivers::{char_devices, CharDevice};
+use alloc::sync::Arc;
 use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+static mut CONSOLE: Option<Arc<dyn CharDevice>> = None;
+/// 为 true 时不再使用字符设备，panic 时设备的发送锁可能被持有
+static USE_SBI: AtomicBool = AtomicBool::new(false);
+
+fn device() -> Option<&'static dyn CharDevice> {
+    if USE_SBI.load(Ordering::Relaxed) {
+        return None;
+    }
+    unsafe { CONSOLE.as_deref() }
+}
+
+/// 选择控制台使用的字符设备，须在 `drivers::init` 之后由主核调用
+pub fn init() {
+    let device = char_devices().into_iter().next();
+    if device.is_none() {
+        crate::info!("no console device found, console uses SBI");
+    }
+    unsafe { CONSOLE = device };
+}
+
+/// 之后的输出都通过 SBI，由 panic 调用
+pub fn fallback_to_sbi() {
+    USE_SBI.store(true, Ordering::Relaxed);
+}
+
+/// 读取一个输入的字节，没有输入时返回 None
+pub fn getchar() -> Option<u8> {
+    match device() {
+        Some(device) => device.read(),
+        None => match console_getchar() {
+            usize::MAX => None,
+            c => Some(c as u8),
+        },
+    }
+}
 
 struct Stdout;
 
 impl Write for Stdout {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for c in s.chars() {
-            console_putchar(c as usize);
+        let device = match device() {
+            Some(device) => device,
+            None => {
+                for c in s.chars() {
+                    console_putchar(c as usize);
+                }
+                return Ok(());
+            }
+        };
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                device.write(b"\r\n");
+            }
+            device.write(line.as_bytes());
         }
         Ok(())
     }
@@ -28,4 +83,4 @@ macro_rules! println {
     ($fmt: literal $(, $($arg: tt)+)?) => {
         $crate::console::print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,124 @@
+//! CLOCK_REALTIME 与 CLOCK_MONOTONIC
+//!
+//! 两者都由 time 寄存器计算：CLOCK_MONOTONIC 为启动以来的时间，
+//! CLOCK_REALTIME 再加上启动时从 RTC 读到的 Unix 时间与之的差
+use super::drivers::goldfish_rtc::GoldfishRtc;
+use crate::arch::timer::{read_time, time_to_duration};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+/// 时钟编号，与 Linux 一致
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ClockId {
+    Realtime = 0,
+    Monotonic = 1,
+}
+
+impl ClockId {
+    pub fn from_usize(id: usize) -> Option<Self> {
+        match id {
+            0 => Some(ClockId::Realtime),
+            1 => Some(ClockId::Monotonic),
+            _ => None,
+        }
+    }
+}
+
+/// 与 Linux 的 `struct timespec` 布局一致
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimeSpec {
+    pub tv_sec: usize,
+    pub tv_nsec: usize,
+}
+
+impl From<Duration> for TimeSpec {
+    fn from(duration: Duration) -> Self {
+        Self {
+            tv_sec: duration.as_secs() as usize,
+            tv_nsec: duration.subsec_nanos() as usize,
+        }
+    }
+}
+
+impl From<TimeSpec> for Duration {
+    fn from(time: TimeSpec) -> Self {
+        Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+    }
+}
+
+/// CLOCK_REALTIME 与 CLOCK_MONOTONIC 之差，单位为纳秒
+static REALTIME_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// 启动时找到的 RTC，设置时间时一并写入
+static mut RTC: Option<GoldfishRtc> = None;
+
+/// 从 RTC 读取当前时间，须在映射 MMIO 之后由主核调用
+pub fn init() {
+    let rtc = GoldfishRtc::probe();
+    match &rtc {
+        Some(rtc) => {
+            let monotonic = clock_gettime(ClockId::Monotonic).as_nanos() as u64;
+            REALTIME_OFFSET.store(rtc.read_time().saturating_sub(monotonic), Ordering::Relaxed);
+            info!("realtime clock: {}", Utc(clock_gettime(ClockId::Realtime)));
+        }
+        None => warn!("no RTC found, CLOCK_REALTIME starts from the Unix epoch"),
+    }
+    unsafe { RTC = rtc };
+}
+
+/// 读取时钟
+pub fn clock_gettime(clock: ClockId) -> Duration {
+    let monotonic = time_to_duration(read_time());
+    match clock {
+        ClockId::Monotonic => monotonic,
+        ClockId::Realtime => {
+            monotonic + Duration::from_nanos(REALTIME_OFFSET.load(Ordering::Relaxed))
+        }
+    }
+}
+
+/// 设置 CLOCK_REALTIME，CLOCK_MONOTONIC 不能设置，返回是否成功
+pub fn clock_settime(clock: ClockId, time: Duration) -> bool {
+    if clock != ClockId::Realtime {
+        return false;
+    }
+    let nanos = time.as_nanos() as u64;
+    let monotonic = clock_gettime(ClockId::Monotonic).as_nanos() as u64;
+    REALTIME_OFFSET.store(nanos.saturating_sub(monotonic), Ordering::Relaxed);
+    if let Some(rtc) = unsafe { &RTC } {
+        rtc.set_time(nanos);
+    }
+    true
+}
+
+/// 以 `YYYY-MM-DD hh:mm:ss UTC` 的形式打印 Unix 时间
+pub struct Utc(pub Duration);
+
+impl fmt::Display for Utc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0.as_secs();
+        let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+        // 由 1970-01-01 起的天数计算公历日期，以 3 月为一年的开始
+        let days = days + 719468;
+        let era = days / 146097;
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        )
+    }
+}
@@ -0,0 +1,49 @@
+//! Goldfish RTC，QEMU virt 上的实时时钟，计数为 Unix 时间的纳秒数
+use crate::arch::config::{GOLDFISH_RTC, KERNEL_MAP_OFFSET};
+use crate::arch::machine::machine;
+use core::ptr::{read_volatile, write_volatile};
+
+/// 读取低 32 位时锁存高 32 位
+const TIME_LOW: usize = 0x00;
+const TIME_HIGH: usize = 0x04;
+
+pub struct GoldfishRtc {
+    /// 寄存器的虚拟地址
+    base: usize,
+}
+
+impl GoldfishRtc {
+    /// 从设备树中查找，设备树不可用时使用 `config::GOLDFISH_RTC`
+    pub fn probe() -> Option<Self> {
+        let machine = machine();
+        let base = match machine.rtc {
+            Some(device) => device.base,
+            None if machine.fdt.is_none() => GOLDFISH_RTC?,
+            None => return None,
+        };
+        Some(Self {
+            base: base + KERNEL_MAP_OFFSET,
+        })
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    /// 当前的 Unix 时间，单位为纳秒
+    pub fn read_time(&self) -> u64 {
+        let low = self.read(TIME_LOW) as u64;
+        let high = self.read(TIME_HIGH) as u64;
+        high << 32 | low
+    }
+
+    /// 设置 Unix 时间，单位为纳秒
+    pub fn set_time(&self, nanos: u64) {
+        self.write(TIME_HIGH, (nanos >> 32) as u32);
+        self.write(TIME_LOW, nanos as u32);
+    }
+}
@@ -0,0 +1,198 @@
+//! 设备驱动
+//!
+//! 驱动在 `DRIVERS` 中登记匹配的设备树 compatible 字符串，启动时遍历设备树，
+//! 为匹配的节点创建驱动实例、注册中断并初始化。上层通过 `BlockDevice`/`CharDevice`
+//! 使用设备，不需要知道具体的驱动。
+//! PLIC 与 RTC 在此之前初始化，不经过注册表
+pub mod goldfish_rtc;
+pub mod ns16550a;
+pub mod plic;
+pub mod virtio_blk;
+pub mod virtio_mmio;
+pub mod virtqueue;
+
+use crate::arch::config::{UART, VIRTIO};
+use crate::arch::machine::{machine, DeviceInfo};
+use crate::kernel::irq::{register_irq, unregister_irq};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use ns16550a::Ns16550a;
+use spin::Mutex;
+use virtio_blk::VirtioBlk;
+
+/// 设备操作的错误
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceError {
+    /// 块号超出设备容量
+    OutOfRange,
+    /// 缓冲区大小与块大小不同
+    InvalidBuffer,
+    /// 写只读设备
+    ReadOnly,
+    /// 设备不支持该操作
+    Unsupported,
+    /// 设备返回错误
+    IoError,
+}
+
+/// 设备驱动
+pub trait Driver: Send + Sync {
+    /// 检查设备树节点描述的设备，不受支持时返回 None，此时不应改变设备的状态
+    fn probe(device: &DeviceInfo) -> Option<Self>
+    where
+        Self: Sized;
+
+    /// 初始化设备，`has_irq` 表示中断是否已注册，未注册时驱动须轮询。失败时返回 false
+    fn init(&self, has_irq: bool) -> bool;
+
+    /// 中断处理，在关中断的中断上下文中调用
+    fn irq(&self) {}
+
+    fn name(&self) -> &'static str;
+
+    fn as_block(self: Arc<Self>) -> Option<Arc<dyn BlockDevice>> {
+        None
+    }
+
+    fn as_char(self: Arc<Self>) -> Option<Arc<dyn CharDevice>> {
+        None
+    }
+}
+
+/// 以固定大小的块为单位读写的设备
+pub trait BlockDevice: Send + Sync {
+    /// 块大小，单位为字节
+    fn block_size(&self) -> usize;
+
+    /// 块数
+    fn num_blocks(&self) -> u64;
+
+    /// 读取第 `block` 块，`buf` 的大小须为块大小
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), DeviceError>;
+
+    /// 写入第 `block` 块，`buf` 的大小须为块大小
+    fn write_block(&self, block: u64, buf: &[u8]) -> Result<(), DeviceError>;
+}
+
+/// 以字节为单位读写的设备
+pub trait CharDevice: Send + Sync {
+    /// 读取一个字节，没有数据时返回 None
+    fn read(&self) -> Option<u8>;
+
+    /// 写入 `bytes`，全部发送后返回
+    fn write(&self, bytes: &[u8]);
+}
+
+struct DriverEntry {
+    /// 匹配的 compatible 字符串
+    compatible: &'static [&'static str],
+    probe: fn(&DeviceInfo) -> Option<Arc<dyn Driver>>,
+}
+
+fn probe<T: Driver + 'static>(device: &DeviceInfo) -> Option<Arc<dyn Driver>> {
+    Some(Arc::new(T::probe(device)?))
+}
+
+/// 驱动注册表，同一节点依次尝试匹配的驱动
+static DRIVERS: &[DriverEntry] = &[
+    DriverEntry {
+        compatible: &["ns16550a"],
+        probe: probe::<Ns16550a>,
+    },
+    DriverEntry {
+        compatible: &["virtio,mmio"],
+        probe: probe::<VirtioBlk>,
+    },
+];
+
+/// 已初始化的设备
+static DEVICES: Mutex<Vec<Arc<dyn Driver>>> = Mutex::new(Vec::new());
+
+/// 为 compatible 满足 `is_compatible` 的设备查找驱动并初始化
+fn probe_device(is_compatible: impl Fn(&str) -> bool, device: DeviceInfo) {
+    let driver = DRIVERS
+        .iter()
+        .filter(|entry| entry.compatible.iter().any(|c| is_compatible(c)))
+        .find_map(|entry| (entry.probe)(&device));
+    let driver = match driver {
+        Some(driver) => driver,
+        None => return,
+    };
+    let has_irq = match device.irq {
+        Some(irq) => {
+            let handler = driver.clone();
+            register_irq(irq, move || handler.irq())
+        }
+        None => false,
+    };
+    if !driver.init(has_irq) {
+        warn!(
+            "{} at {:#x}: failed to initialize",
+            driver.name(),
+            device.base
+        );
+        if let (true, Some(irq)) = (has_irq, device.irq) {
+            unregister_irq(irq);
+        }
+        return;
+    }
+    info!(
+        "{} at {:#x}{}",
+        driver.name(),
+        device.base,
+        if has_irq { "" } else { ", polling" }
+    );
+    DEVICES.lock().push(driver);
+}
+
+/// 遍历设备树初始化各设备，设备树不可用时使用 `config` 中的设备。
+/// 须在 `irq::init` 之后由主核调用
+pub fn init() {
+    let machine = machine();
+    if let Some(fdt) = machine.fdt {
+        for node in fdt.nodes() {
+            if node.prop_str("status") == Some("disabled") {
+                continue;
+            }
+            if let Some((base, size)) = node.reg(0) {
+                let device = DeviceInfo {
+                    base,
+                    size,
+                    irq: node.interrupt(),
+                };
+                probe_device(|c| node.is_compatible(c), device);
+            }
+        }
+        return;
+    }
+    let defaults = UART
+        .iter()
+        .map(|&(base, irq)| ("ns16550a", base, irq))
+        .chain(VIRTIO.iter().map(|&(base, irq)| ("virtio,mmio", base, irq)));
+    for (compatible, base, irq) in defaults {
+        let device = DeviceInfo {
+            base,
+            size: 0x1000,
+            irq: Some(irq),
+        };
+        probe_device(|c| c == compatible, device);
+    }
+}
+
+/// 所有块设备，按设备树中的顺序排列
+pub fn block_devices() -> Vec<Arc<dyn BlockDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter_map(|driver| driver.clone().as_block())
+        .collect()
+}
+
+/// 所有字符设备，按设备树中的顺序排列
+pub fn char_devices() -> Vec<Arc<dyn CharDevice>> {
+    DEVICES
+        .lock()
+        .iter()
+        .filter_map(|driver| driver.clone().as_char())
+        .collect()
+}
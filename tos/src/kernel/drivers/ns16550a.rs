@@ -0,0 +1,186 @@
+//! NS16550A 串口，QEMU virt 上的 UART0
+//!
+//! 波特率由 SBI 设置，这里只打开收发 FIFO 与接收中断，接收到的数据由中断放入环形缓冲区
+use super::{CharDevice, Driver};
+use crate::arch::config::KERNEL_MAP_OFFSET;
+use crate::arch::interface::Interrupt;
+use crate::arch::machine::DeviceInfo;
+use crate::arch::InterruptImpl;
+use alloc::sync::Arc;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+/// 接收缓冲（读）/ 发送保持（写）
+const RBR_THR: usize = 0;
+/// 中断使能
+const IER: usize = 1;
+/// FIFO 控制（写）
+const FCR: usize = 2;
+/// 线路控制
+const LCR: usize = 3;
+/// Modem 控制
+const MCR: usize = 4;
+/// 线路状态
+const LSR: usize = 5;
+
+/// IER：接收到数据时中断
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+/// FCR：打开并清空收发 FIFO
+const FCR_ENABLE_CLEAR: u8 = 0b111;
+/// LCR：8 位数据位，无校验，1 位停止位
+const LCR_8N1: u8 = 0b11;
+/// MCR：DTR | RTS | OUT2，OUT2 控制中断输出
+const MCR_DTR_RTS_OUT2: u8 = 0b1011;
+/// LSR：接收缓冲中有数据
+const LSR_DATA_READY: u8 = 1 << 0;
+/// LSR：发送 FIFO 为空
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// 发送 FIFO 的深度，发送 FIFO 为空时可以连续写入这么多字节
+const TX_FIFO_DEPTH: usize = 16;
+
+/// 输入缓冲区的大小，满时丢弃新收到的字节
+const INPUT_BUFFER_SIZE: usize = 256;
+
+struct RingBuffer {
+    buf: [u8; INPUT_BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        Self {
+            buf: [0; INPUT_BUFFER_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == INPUT_BUFFER_SIZE {
+            return false;
+        }
+        self.buf[(self.head + self.len) % INPUT_BUFFER_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % INPUT_BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+pub struct Ns16550a {
+    /// 寄存器的虚拟地址
+    base: usize,
+    /// 打开了接收中断，输入由中断放入 `input`
+    rx_irq: AtomicBool,
+    input: Mutex<RingBuffer>,
+    /// 保证多个核写入发送 FIFO 时不会溢出
+    tx_lock: Mutex<()>,
+}
+
+impl Ns16550a {
+    fn read_reg(&self, offset: usize) -> u8 {
+        unsafe { read_volatile((self.base + offset) as *const u8) }
+    }
+
+    fn write_reg(&self, offset: usize, value: u8) {
+        unsafe { write_volatile((self.base + offset) as *mut u8, value) }
+    }
+
+    /// 读取一个收到的字节，没有数据时返回 None
+    fn try_read(&self) -> Option<u8> {
+        if self.read_reg(LSR) & LSR_DATA_READY != 0 {
+            Some(self.read_reg(RBR_THR))
+        } else {
+            None
+        }
+    }
+
+    /// 发送 `bytes`，每次等待发送 FIFO 清空后写入一整个 FIFO
+    fn write_bytes(&self, bytes: &[u8]) {
+        for chunk in bytes.chunks(TX_FIFO_DEPTH) {
+            while self.read_reg(LSR) & LSR_THR_EMPTY == 0 {
+                core::hint::spin_loop();
+            }
+            for &byte in chunk {
+                self.write_reg(RBR_THR, byte);
+            }
+        }
+    }
+}
+
+impl Driver for Ns16550a {
+    fn probe(device: &DeviceInfo) -> Option<Self> {
+        Some(Self {
+            base: device.base + KERNEL_MAP_OFFSET,
+            rx_irq: AtomicBool::new(false),
+            input: Mutex::new(RingBuffer::new()),
+            tx_lock: Mutex::new(()),
+        })
+    }
+
+    /// 打开收发 FIFO，有中断时打开接收中断
+    fn init(&self, has_irq: bool) -> bool {
+        self.write_reg(IER, 0);
+        self.write_reg(LCR, LCR_8N1);
+        self.write_reg(FCR, FCR_ENABLE_CLEAR);
+        self.write_reg(MCR, MCR_DTR_RTS_OUT2);
+        if has_irq {
+            self.rx_irq.store(true, Ordering::Relaxed);
+            self.write_reg(IER, IER_RX_AVAILABLE);
+        }
+        true
+    }
+
+    /// 接收中断，将收到的字节全部放入输入缓冲区
+    fn irq(&self) {
+        let mut input = self.input.lock();
+        while let Some(byte) = self.try_read() {
+            input.push(byte);
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        "ns16550a"
+    }
+
+    fn as_char(self: Arc<Self>) -> Option<Arc<dyn CharDevice>> {
+        Some(self)
+    }
+}
+
+impl CharDevice for Ns16550a {
+    fn read(&self) -> Option<u8> {
+        if !self.rx_irq.load(Ordering::Relaxed) {
+            return self.try_read();
+        }
+        // 关中断，避免与本核的接收中断争用锁
+        unsafe {
+            let enabled = InterruptImpl::disable();
+            let byte = self.input.lock().pop();
+            InterruptImpl::restore(enabled);
+            byte
+        }
+    }
+
+    fn write(&self, bytes: &[u8]) {
+        // 关中断，避免中断处理中输出时与本核争用锁
+        unsafe {
+            let enabled = InterruptImpl::disable();
+            let guard = self.tx_lock.lock();
+            self.write_bytes(bytes);
+            drop(guard);
+            InterruptImpl::restore(enabled);
+        }
+    }
+}
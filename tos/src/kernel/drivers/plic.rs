@@ -0,0 +1,89 @@
+//! 平台级中断控制器 PLIC
+//!
+//! 每个核的 S 态是一个上下文，QEMU virt 与 k210 上 `hart` 号核的 S 态上下文为 `2 * hart + 1`。
+//! 中断号 0 保留，表示没有待处理的中断
+use crate::arch::config::{KERNEL_MAP_OFFSET, PLIC};
+use crate::arch::machine::machine;
+use core::ptr::{read_volatile, write_volatile};
+
+/// 各中断源的优先级，每个 4 字节
+const PRIORITY: usize = 0x00_0000;
+/// 各上下文的中断使能位图
+const ENABLE: usize = 0x00_2000;
+const ENABLE_STRIDE: usize = 0x80;
+/// 各上下文的优先级阈值，其后 4 字节为 claim/complete 寄存器
+const THRESHOLD: usize = 0x20_0000;
+const CLAIM: usize = 0x20_0004;
+const CONTEXT_STRIDE: usize = 0x1000;
+
+pub struct Plic {
+    /// 寄存器的虚拟地址
+    base: usize,
+}
+
+impl Plic {
+    /// 从设备树中查找，设备树不可用时使用 `config::PLIC`
+    pub fn probe() -> Option<Self> {
+        let machine = machine();
+        let base = match machine.plic {
+            Some(device) => device.base,
+            None if machine.fdt.is_none() => PLIC?,
+            None => return None,
+        };
+        Some(Self {
+            base: base + KERNEL_MAP_OFFSET,
+        })
+    }
+
+    /// `hart` 号核 S 态的上下文号
+    fn context(hart: usize) -> usize {
+        2 * hart + 1
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    /// 设置中断源的优先级，为 0 时不会触发
+    pub fn set_priority(&self, irq: u32, priority: u32) {
+        self.write(PRIORITY + 4 * irq as usize, priority);
+    }
+
+    fn enable_offset(hart: usize, irq: u32) -> usize {
+        ENABLE + ENABLE_STRIDE * Self::context(hart) + 4 * (irq as usize / 32)
+    }
+
+    /// 允许 `irq` 发送到 `hart` 号核
+    pub fn enable(&self, hart: usize, irq: u32) {
+        let offset = Self::enable_offset(hart, irq);
+        self.write(offset, self.read(offset) | 1 << (irq % 32));
+    }
+
+    /// 禁止 `irq` 发送到 `hart` 号核
+    pub fn disable(&self, hart: usize, irq: u32) {
+        let offset = Self::enable_offset(hart, irq);
+        self.write(offset, self.read(offset) & !(1 << (irq % 32)));
+    }
+
+    /// 设置 `hart` 号核的优先级阈值，只有优先级高于阈值的中断才会发送到该核
+    pub fn set_threshold(&self, hart: usize, threshold: u32) {
+        self.write(THRESHOLD + CONTEXT_STRIDE * Self::context(hart), threshold);
+    }
+
+    /// 领取 `hart` 号核上优先级最高的待处理中断
+    pub fn claim(&self, hart: usize) -> Option<u32> {
+        match self.read(CLAIM + CONTEXT_STRIDE * Self::context(hart)) {
+            0 => None,
+            irq => Some(irq),
+        }
+    }
+
+    /// 通知 PLIC `irq` 已处理完，之后才会再次发送该中断
+    pub fn complete(&self, hart: usize, irq: u32) {
+        self.write(CLAIM + CONTEXT_STRIDE * Self::context(hart), irq);
+    }
+}
@@ -0,0 +1,211 @@
+//! virtio 块设备，以 512 字节的扇区为单位读写
+//!
+//! 请求的头部、数据与状态放在堆中一起提交给设备，完成后再复制到调用者的缓冲区。
+//! 注册中断之前轮询已用环等待完成，注册之后在等待中断时休眠
+use super::virtio_mmio::{DeviceType, VirtioMmio};
+use super::virtqueue::{virt_to_phys, Buffer, VirtQueue};
+use super::{BlockDevice, DeviceError, Driver};
+use crate::arch::interface::{Cpu, Interrupt};
+use crate::arch::machine::DeviceInfo;
+use crate::arch::{CpuImpl, InterruptImpl};
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+/// 扇区大小
+pub const SECTOR_SIZE: usize = 512;
+/// 请求队列的长度，每个请求占 3 个描述符
+const QUEUE_SIZE: u16 = 16;
+
+/// 设备只读
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+
+/// 请求类型
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+
+/// 请求状态
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+#[repr(C)]
+struct BlkRequest {
+    req_type: u32,
+    reserved: u32,
+    sector: u64,
+    data: [u8; SECTOR_SIZE],
+    status: u8,
+}
+
+pub struct VirtioBlk {
+    transport: VirtioMmio,
+    queue: Mutex<VirtQueue>,
+    /// 容量，单位为扇区
+    capacity: AtomicU64,
+    read_only: AtomicBool,
+    /// 已注册中断，等待时可以休眠
+    irq_enabled: AtomicBool,
+}
+
+impl VirtioBlk {
+    fn new_request(&self, req_type: u32, sector: u64) -> Result<Box<BlkRequest>, DeviceError> {
+        if sector >= self.num_blocks() {
+            return Err(DeviceError::OutOfRange);
+        }
+        Ok(Box::new(BlkRequest {
+            req_type,
+            reserved: 0,
+            sector,
+            data: [0; SECTOR_SIZE],
+            status: 0xff,
+        }))
+    }
+
+    /// 提交请求并等待完成，`read` 为 true 时数据由设备写入
+    fn submit(&self, request: &mut BlkRequest, read: bool) -> Result<(), DeviceError> {
+        let base = virt_to_phys(request as *mut BlkRequest as usize);
+        let header_len = 2 * size_of::<u32>() + size_of::<u64>();
+        let buffers = [
+            Buffer {
+                pa: base,
+                len: header_len as u32,
+                device_writable: false,
+            },
+            Buffer {
+                pa: base + header_len,
+                len: SECTOR_SIZE as u32,
+                device_writable: read,
+            },
+            Buffer {
+                pa: base + header_len + SECTOR_SIZE,
+                len: 1,
+                device_writable: true,
+            },
+        ];
+        let head = self.wait_queue(|queue| queue.add(&buffers));
+        self.transport.notify(0);
+        self.wait_queue(|queue| queue.take_done(head));
+        match unsafe { core::ptr::read_volatile(&request.status) } {
+            VIRTIO_BLK_S_OK => Ok(()),
+            VIRTIO_BLK_S_UNSUPP => Err(DeviceError::Unsupported),
+            _ => Err(DeviceError::IoError),
+        }
+    }
+
+    /// 在关中断的情况下处理已完成的请求并调用 `f`，直到 `f` 返回 Some
+    fn wait_queue<T>(&self, mut f: impl FnMut(&mut VirtQueue) -> Option<T>) -> T {
+        loop {
+            unsafe {
+                let enabled = InterruptImpl::disable();
+                let result = {
+                    let mut queue = self.queue.lock();
+                    queue.collect_used();
+                    f(&mut queue)
+                };
+                // 关中断时 wfi 同样会被待处理的中断唤醒，不会错过完成中断
+                if result.is_none() && self.irq_enabled.load(Ordering::Relaxed) {
+                    CpuImpl::wait_for_interrupt();
+                }
+                InterruptImpl::restore(enabled);
+                match result {
+                    Some(result) => return result,
+                    None => core::hint::spin_loop(),
+                }
+            }
+        }
+    }
+}
+
+impl Driver for VirtioBlk {
+    /// 只接受块设备，其他类型的 virtio 设备留给对应的驱动
+    fn probe(device: &DeviceInfo) -> Option<Self> {
+        let transport = VirtioMmio::probe(device.base)?;
+        if transport.device_type() != Some(DeviceType::Block) {
+            return None;
+        }
+        let max_size = transport.max_queue_size(0);
+        if max_size == 0 {
+            warn!("virtio-blk: no request queue");
+            return None;
+        }
+        Some(Self {
+            queue: Mutex::new(VirtQueue::new(QUEUE_SIZE.min(max_size as u16))),
+            transport,
+            capacity: AtomicU64::new(0),
+            read_only: AtomicBool::new(false),
+            irq_enabled: AtomicBool::new(false),
+        })
+    }
+
+    fn init(&self, has_irq: bool) -> bool {
+        let mut read_only = false;
+        let accepted = self.transport.begin_init(|features| {
+            read_only = features & VIRTIO_BLK_F_RO != 0;
+            features & VIRTIO_BLK_F_RO
+        });
+        if !accepted {
+            warn!("virtio-blk: features not accepted");
+            return false;
+        }
+        self.transport.setup_queue(0, &self.queue.lock());
+        self.transport.finish_init();
+        let capacity = self.transport.read_config::<u64>(0);
+        self.capacity.store(capacity, Ordering::Relaxed);
+        self.read_only.store(read_only, Ordering::Relaxed);
+        self.irq_enabled.store(has_irq, Ordering::Relaxed);
+        info!(
+            "virtio-blk: {} sectors{}",
+            capacity,
+            if read_only { ", read-only" } else { "" }
+        );
+        true
+    }
+
+    /// 回收已完成的请求
+    fn irq(&self) {
+        self.transport.ack_interrupt();
+        self.queue.lock().collect_used();
+    }
+
+    fn name(&self) -> &'static str {
+        "virtio-blk"
+    }
+
+    fn as_block(self: Arc<Self>) -> Option<Arc<dyn BlockDevice>> {
+        Some(self)
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn num_blocks(&self) -> u64 {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), DeviceError> {
+        if buf.len() != SECTOR_SIZE {
+            return Err(DeviceError::InvalidBuffer);
+        }
+        let mut request = self.new_request(VIRTIO_BLK_T_IN, block)?;
+        self.submit(&mut request, true)?;
+        buf.copy_from_slice(&request.data);
+        Ok(())
+    }
+
+    fn write_block(&self, block: u64, buf: &[u8]) -> Result<(), DeviceError> {
+        if buf.len() != SECTOR_SIZE {
+            return Err(DeviceError::InvalidBuffer);
+        }
+        if self.read_only.load(Ordering::Relaxed) {
+            return Err(DeviceError::ReadOnly);
+        }
+        let mut request = self.new_request(VIRTIO_BLK_T_OUT, block)?;
+        request.data.copy_from_slice(buf);
+        self.submit(&mut request, false)
+    }
+}
@@ -0,0 +1,204 @@
+//! virtio-mmio 传输层，支持旧版（version 1）与新版（version 2）接口
+//!
+//! QEMU virt 默认使用旧版接口，加上 `-global virtio-mmio.force-legacy=false` 后使用新版
+use super::virtqueue::VirtQueue;
+use crate::arch::config::{KERNEL_MAP_OFFSET, PAGE_SIZE};
+use core::ptr::{read_volatile, write_volatile};
+
+const MAGIC_VALUE: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+/// 旧版：客户机页大小，用于计算 QUEUE_PFN
+const GUEST_PAGE_SIZE: usize = 0x028;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+/// 旧版：已用环的对齐
+const QUEUE_ALIGN: usize = 0x03c;
+/// 旧版：队列所在的物理页号
+const QUEUE_PFN: usize = 0x040;
+/// 新版
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+/// 新版：描述符表、可用环、已用环的物理地址
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+/// 设备相关的配置空间
+const CONFIG: usize = 0x100;
+
+/// "virt" 的小端表示
+const MAGIC: u32 = 0x7472_6976;
+
+/// 设备状态
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+/// 新版设备必须协商的特性
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// 设备类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    Network = 1,
+    Block = 2,
+    Console = 3,
+    Entropy = 4,
+}
+
+impl DeviceType {
+    fn from_u32(id: u32) -> Option<Self> {
+        match id {
+            1 => Some(DeviceType::Network),
+            2 => Some(DeviceType::Block),
+            3 => Some(DeviceType::Console),
+            4 => Some(DeviceType::Entropy),
+            _ => None,
+        }
+    }
+}
+
+pub struct VirtioMmio {
+    /// 寄存器的虚拟地址
+    base: usize,
+    version: u32,
+}
+
+impl VirtioMmio {
+    /// 检查 `base` 处是否为 virtio-mmio 设备，设备号为 0 的空槽位返回 None
+    pub fn probe(base: usize) -> Option<Self> {
+        let transport = Self {
+            base: base + KERNEL_MAP_OFFSET,
+            version: 0,
+        };
+        if transport.read(MAGIC_VALUE) != MAGIC || transport.read(DEVICE_ID) == 0 {
+            return None;
+        }
+        let version = transport.read(VERSION);
+        if version != 1 && version != 2 {
+            warn!(
+                "virtio-mmio at {:#x}: unsupported version {}",
+                base, version
+            );
+            return None;
+        }
+        Some(Self {
+            version,
+            ..transport
+        })
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.base + offset) as *const u32) }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.base + offset) as *mut u32, value) }
+    }
+
+    pub fn is_legacy(&self) -> bool {
+        self.version == 1
+    }
+
+    /// 设备类型，未知类型返回 None
+    pub fn device_type(&self) -> Option<DeviceType> {
+        DeviceType::from_u32(self.read(DEVICE_ID))
+    }
+
+    /// 读取配置空间中 `offset` 处的值
+    pub fn read_config<T: Copy>(&self, offset: usize) -> T {
+        unsafe { read_volatile((self.base + CONFIG + offset) as *const T) }
+    }
+
+    /// 复位设备并协商特性，`negotiate` 由设备提供的特性得到驱动接受的特性。
+    /// 设备不接受时返回 false
+    pub fn begin_init(&self, negotiate: impl FnOnce(u64) -> u64) -> bool {
+        self.write(STATUS, 0);
+        self.write(STATUS, STATUS_ACKNOWLEDGE);
+        self.write(STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let low = self.read(DEVICE_FEATURES) as u64;
+        self.write(DEVICE_FEATURES_SEL, 1);
+        let high = self.read(DEVICE_FEATURES) as u64;
+        let device_features = high << 32 | low;
+        let mut features = negotiate(device_features);
+        if !self.is_legacy() {
+            features |= device_features & VIRTIO_F_VERSION_1;
+        }
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, features as u32);
+        self.write(DRIVER_FEATURES_SEL, 1);
+        self.write(DRIVER_FEATURES, (features >> 32) as u32);
+
+        if self.is_legacy() {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            return true;
+        }
+        let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+        self.write(STATUS, status);
+        if self.read(STATUS) & STATUS_FEATURES_OK == 0 {
+            self.write(STATUS, status | STATUS_FAILED);
+            return false;
+        }
+        true
+    }
+
+    /// 第 `index` 个队列的最大长度，队列不存在时为 0
+    pub fn max_queue_size(&self, index: u32) -> u32 {
+        self.write(QUEUE_SEL, index);
+        self.read(QUEUE_NUM_MAX)
+    }
+
+    /// 将 `queue` 设置为第 `index` 个队列
+    pub fn setup_queue(&self, index: u32, queue: &VirtQueue) {
+        self.write(QUEUE_SEL, index);
+        self.write(QUEUE_NUM, queue.size() as u32);
+        if self.is_legacy() {
+            self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(QUEUE_PFN, (queue.desc_pa() / PAGE_SIZE) as u32);
+            return;
+        }
+        let (desc, avail, used) = (queue.desc_pa(), queue.avail_pa(), queue.used_pa());
+        self.write(QUEUE_DESC_LOW, desc as u32);
+        self.write(QUEUE_DESC_HIGH, (desc >> 32) as u32);
+        self.write(QUEUE_DRIVER_LOW, avail as u32);
+        self.write(QUEUE_DRIVER_HIGH, (avail >> 32) as u32);
+        self.write(QUEUE_DEVICE_LOW, used as u32);
+        self.write(QUEUE_DEVICE_HIGH, (used >> 32) as u32);
+        self.write(QUEUE_READY, 1);
+    }
+
+    /// 初始化完成，设备开始工作
+    pub fn finish_init(&self) {
+        let status = self.read(STATUS);
+        self.write(STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    /// 通知设备第 `index` 个队列有新的请求
+    pub fn notify(&self, index: u32) {
+        self.write(QUEUE_NOTIFY, index);
+    }
+
+    /// 读取并清除中断状态
+    pub fn ack_interrupt(&self) -> u32 {
+        let status = self.read(INTERRUPT_STATUS);
+        if status != 0 {
+            self.write(INTERRUPT_ACK, status);
+        }
+        status
+    }
+}
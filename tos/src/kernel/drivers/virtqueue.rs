@@ -0,0 +1,201 @@
+//! virtio 的分离式虚拟队列
+//!
+//! 队列内存是从帧分配器分配的物理连续的页帧，按旧版接口的布局连续存放：
+//! 描述符表与可用环在前，已用环从下一页开始，新版接口也使用同样的布局
+use crate::arch::config::{KERNEL_MAP_OFFSET, PAGE_SIZE};
+use crate::kernel::mm::address::PA;
+use crate::kernel::mm::frame_allocator::{frame_alloc_contiguous, ContiguousFrames};
+use core::ptr::{addr_of_mut, read_volatile, write_bytes, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+/// 队列的最大长度
+pub const MAX_QUEUE_SIZE: u16 = 64;
+
+/// 描述符的标志
+const DESC_F_NEXT: u16 = 1;
+/// 设备写、驱动读的缓冲区
+const DESC_F_WRITE: u16 = 2;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+/// 线性映射中地址的物理地址，用于 DMA。
+/// 堆中的对象也在线性映射中，且物理连续：slab 对象不跨页，更大的分配是连续的页帧
+pub fn virt_to_phys(va: usize) -> usize {
+    debug_assert!(va >= KERNEL_MAP_OFFSET);
+    va - KERNEL_MAP_OFFSET
+}
+
+/// 请求中的一段缓冲区
+pub struct Buffer {
+    /// 物理地址
+    pub pa: usize,
+    pub len: u32,
+    /// 由设备写入
+    pub device_writable: bool,
+}
+
+pub struct VirtQueue {
+    /// 队列内存
+    frames: ContiguousFrames,
+    desc: *mut Descriptor,
+    /// 可用环：flags, idx, ring[size], used_event
+    avail: *mut u16,
+    /// 已用环：flags, idx, ring[size], avail_event
+    used: *mut u16,
+    size: u16,
+    /// 空闲描述符组成的链表
+    free_head: u16,
+    num_free: u16,
+    /// 已处理到的已用环位置
+    last_used: u16,
+    /// 以首个描述符为下标，请求完成后记录设备写入的字节数
+    done: [Option<u32>; MAX_QUEUE_SIZE as usize],
+}
+
+// 队列内存只通过 VirtQueue 访问
+unsafe impl Send for VirtQueue {}
+
+impl VirtQueue {
+    fn used_offset(size: usize) -> usize {
+        let avail_end = 16 * size + 2 * (3 + size);
+        (avail_end + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+    }
+
+    /// 分配长度为 `size` 的队列，`size` 须为 2 的幂且不超过 `MAX_QUEUE_SIZE`
+    pub fn new(size: u16) -> Self {
+        assert!(size.is_power_of_two() && size <= MAX_QUEUE_SIZE);
+        let n = size as usize;
+        let used_offset = Self::used_offset(n);
+        let pages = (used_offset + 2 * 3 + 8 * n + PAGE_SIZE - 1) / PAGE_SIZE;
+        let order = pages.next_power_of_two().trailing_zeros() as usize;
+        let frames = frame_alloc_contiguous(order).expect("failed to allocate virtqueue");
+        let mem = frames.va().0 as *mut u8;
+        unsafe { write_bytes(mem, 0, frames.pages() * PAGE_SIZE) };
+        let desc = mem as *mut Descriptor;
+        for i in 0..size - 1 {
+            unsafe { (*desc.add(i as usize)).next = i + 1 };
+        }
+        Self {
+            frames,
+            desc,
+            avail: unsafe { mem.add(16 * n) } as *mut u16,
+            used: unsafe { mem.add(used_offset) } as *mut u16,
+            size,
+            free_head: 0,
+            num_free: size,
+            last_used: 0,
+            done: [None; MAX_QUEUE_SIZE as usize],
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// 队列内存中 `ptr` 的物理地址
+    fn pa<T>(&self, ptr: *mut T) -> usize {
+        usize::from(PA::from(self.frames.ppn())) + (ptr as usize - self.frames.va().0)
+    }
+
+    pub fn desc_pa(&self) -> usize {
+        self.pa(self.desc)
+    }
+
+    pub fn avail_pa(&self) -> usize {
+        self.pa(self.avail)
+    }
+
+    pub fn used_pa(&self) -> usize {
+        self.pa(self.used)
+    }
+
+    /// 将 `buffers` 组成一个请求放入可用环，返回首个描述符的下标，描述符不足时返回 None。
+    /// 之后需要通知设备
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.num_free as usize {
+            return None;
+        }
+        let head = self.free_head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let desc = unsafe { &mut *self.desc.add(self.free_head as usize) };
+            desc.addr = buffer.pa as u64;
+            desc.len = buffer.len;
+            desc.flags = if buffer.device_writable {
+                DESC_F_WRITE
+            } else {
+                0
+            };
+            if i + 1 < buffers.len() {
+                desc.flags |= DESC_F_NEXT;
+            }
+            self.free_head = desc.next;
+        }
+        self.num_free -= buffers.len() as u16;
+        self.done[head as usize] = None;
+
+        unsafe {
+            let idx = read_volatile(self.avail.add(1));
+            write_volatile(self.avail.add(2 + (idx % self.size) as usize), head);
+            // 设备看到新的 idx 之前，描述符与可用环中的内容须已写入
+            fence(Ordering::SeqCst);
+            write_volatile(self.avail.add(1), idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+        }
+        Some(head)
+    }
+
+    /// 处理已用环中新完成的请求，回收它们的描述符
+    pub fn collect_used(&mut self) {
+        loop {
+            let used_idx = unsafe { read_volatile(self.used.add(1)) };
+            if self.last_used == used_idx {
+                break;
+            }
+            fence(Ordering::SeqCst);
+            let elem = unsafe {
+                let ring = self.used.add(2) as *mut UsedElem;
+                let elem = ring.add((self.last_used % self.size) as usize);
+                (
+                    read_volatile(addr_of_mut!((*elem).id)),
+                    read_volatile(addr_of_mut!((*elem).len)),
+                )
+            };
+            self.last_used = self.last_used.wrapping_add(1);
+            let head = elem.0 as u16;
+            self.free_chain(head);
+            self.done[head as usize] = Some(elem.1);
+        }
+    }
+
+    /// 将以 `head` 开始的描述符链放回空闲链表
+    fn free_chain(&mut self, head: u16) {
+        let mut index = head;
+        loop {
+            let desc = unsafe { &mut *self.desc.add(index as usize) };
+            self.num_free += 1;
+            if desc.flags & DESC_F_NEXT == 0 {
+                desc.next = self.free_head;
+                break;
+            }
+            index = desc.next;
+        }
+        self.free_head = head;
+    }
+
+    /// 以 `head` 开始的请求完成时返回设备写入的字节数，并清除完成标记
+    pub fn take_done(&mut self, head: u16) -> Option<u32> {
+        self.done[head as usize].take()
+    }
+}
@@ -0,0 +1,112 @@
+//! 外部中断的注册与分发
+//!
+//! 设备驱动通过 `register_irq` 为 PLIC 中断号注册处理函数，中断会发送到所有已初始化的核，
+//! 由最先 claim 的核处理。处理函数在中断上下文中执行，此时中断关闭，应尽快返回
+use super::drivers::plic::Plic;
+use crate::arch::interface::{Cpu, Interrupt};
+use crate::arch::{CpuImpl, InterruptImpl};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+pub type IrqHandler = Arc<dyn Fn() + Send + Sync>;
+
+/// 注册的中断处理函数，以中断号为键
+static HANDLERS: Mutex<BTreeMap<u32, IrqHandler>> = Mutex::new(BTreeMap::new());
+/// 已初始化的核的位图，注册中断时为这些核打开该中断
+static ONLINE_HARTS: AtomicUsize = AtomicUsize::new(0);
+static mut PLIC: Option<Plic> = None;
+
+fn plic() -> Option<&'static Plic> {
+    unsafe { PLIC.as_ref() }
+}
+
+/// 在关中断的情况下访问处理函数表，避免与本核的外部中断争用锁
+fn with_handlers<T>(f: impl FnOnce(&mut BTreeMap<u32, IrqHandler>) -> T) -> T {
+    unsafe {
+        let enabled = InterruptImpl::disable();
+        let result = f(&mut HANDLERS.lock());
+        InterruptImpl::restore(enabled);
+        result
+    }
+}
+
+/// 查找 PLIC 并初始化当前核，须在映射 MMIO 之后由主核调用
+pub fn init() {
+    let plic = Plic::probe();
+    if plic.is_none() {
+        warn!("no PLIC found, external interrupts are disabled");
+    }
+    unsafe { PLIC = plic };
+    init_hart();
+}
+
+/// 接收优先级大于 0 的所有中断，并打开已注册的中断，每个核都需要调用
+pub fn init_hart() {
+    let plic = match plic() {
+        Some(plic) => plic,
+        None => return,
+    };
+    let hart = CpuImpl::id();
+    with_handlers(|handlers| {
+        plic.set_threshold(hart, 0);
+        for &irq in handlers.keys() {
+            plic.enable(hart, irq);
+        }
+        ONLINE_HARTS.fetch_or(1 << hart, Ordering::Relaxed);
+    });
+}
+
+/// 为 `irq` 注册处理函数并打开该中断，已有处理函数时替换。
+/// 没有 PLIC 时返回 false
+pub fn register_irq(irq: u32, handler: impl Fn() + Send + Sync + 'static) -> bool {
+    let plic = match plic() {
+        Some(plic) => plic,
+        None => return false,
+    };
+    assert_ne!(irq, 0, "irq 0 is reserved");
+    with_handlers(|handlers| {
+        handlers.insert(irq, Arc::new(handler));
+        plic.set_priority(irq, 1);
+        let online = ONLINE_HARTS.load(Ordering::Relaxed);
+        for hart in (0..usize::BITS as usize).filter(|hart| online & 1 << hart != 0) {
+            plic.enable(hart, irq);
+        }
+    });
+    true
+}
+
+/// 注销 `irq` 的处理函数并关闭该中断
+pub fn unregister_irq(irq: u32) {
+    let plic = match plic() {
+        Some(plic) => plic,
+        None => return,
+    };
+    with_handlers(|handlers| {
+        handlers.remove(&irq);
+        plic.set_priority(irq, 0);
+    });
+}
+
+/// 处理 S 态外部中断，由 `handle_trap` 在关中断的情况下调用
+pub fn handle_external() {
+    let plic = match plic() {
+        Some(plic) => plic,
+        None => return,
+    };
+    let hart = CpuImpl::id();
+    // 一次处理完所有待处理的中断
+    while let Some(irq) = plic.claim(hart) {
+        // 复制出处理函数后再调用，处理函数中可以注册或注销中断
+        let handler = HANDLERS.lock().get(&irq).cloned();
+        match handler {
+            Some(handler) => handler(),
+            None => {
+                warn!("unhandled irq {}, disabled", irq);
+                plic.set_priority(irq, 0);
+            }
+        }
+        plic.complete(hart, irq);
+    }
+}
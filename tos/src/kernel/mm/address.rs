@@ -1,8 +1,14 @@
 use super::page_table::PTE;
-use crate::arch::config::{KERNEL_MAP_OFFSET, PAGE_SIZE, PAGE_SIZE_BITS};
+use crate::arch::{interface::Mmu, MmuImpl};
 
 use core::{fmt::Debug, iter::Step, mem::size_of};
 
+/// PAGE_SIZE = 1 << PAGE_SIZE_BITS
+pub const PAGE_SIZE_BITS: usize = MmuImpl::PAGE_SIZE_BITS;
+pub const PAGE_SIZE: usize = 1 << PAGE_SIZE_BITS;
+/// 内核使用线性映射的偏移量
+pub const KERNEL_MAP_OFFSET: usize = MmuImpl::KERNEL_MAP_OFFSET;
+
 #[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
 pub struct PA(pub usize);
 
@@ -123,12 +129,12 @@ impl VA {
 impl PPN {
     pub fn get_bytes_array(&self) -> &'static mut [u8] {
         let pa: PA = self.clone().into();
-        unsafe { core::slice::from_raw_parts_mut(pa.0 as *mut u8, 4096) }
+        unsafe { core::slice::from_raw_parts_mut(pa.0 as *mut u8, PAGE_SIZE) }
     }
 
     pub fn get_pte_array(&self) -> &'static mut [PTE] {
         let pa: PA = self.clone().into();
-        unsafe { core::slice::from_raw_parts_mut(pa.0 as *mut PTE, 512) }
+        unsafe { core::slice::from_raw_parts_mut(pa.0 as *mut PTE, PAGE_SIZE / size_of::<PTE>()) }
     }
 
     pub fn get_mut<T>(&self) -> &'static mut T {
@@ -138,12 +144,13 @@ impl PPN {
 }
 
 impl VPN {
-    pub fn indexes(&self) -> [usize; 3] {
+    /// 在 `levels` 级页表中各级的下标，从根页表开始，只有前 `levels` 项有效
+    pub fn indexes(&self, levels: usize) -> [usize; MmuImpl::MAX_LEVELS] {
         let mut vpn = self.0;
-        let mut idx = [0usize; 3];
-        for i in (0..3).rev() {
-            idx[i] = vpn & 511;
-            vpn >>= 9;
+        let mut idx = [0usize; MmuImpl::MAX_LEVELS];
+        for i in (0..levels).rev() {
+            idx[i] = vpn & ((1 << MmuImpl::INDEX_BITS) - 1);
+            vpn >>= MmuImpl::INDEX_BITS;
         }
 
         idx
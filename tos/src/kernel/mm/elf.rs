@@ -0,0 +1,226 @@
+//! ELF64 可执行文件解析
+//!
+//! 只支持小端序、静态链接的 RISC-V 可执行文件。解析时检查所有偏移与大小，
+//! 格式错误的文件返回 `ElfError`，不会越界访问
+use super::space::MemorySet;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+
+/// ELF 头的大小
+const EHDR_SIZE: usize = 64;
+/// 程序头的大小
+const PHDR_SIZE: usize = 56;
+
+/// 段的权限
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+/// ELF 文件格式错误
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    /// 文件在头部或程序头表中截断
+    Truncated,
+    /// 魔数不正确
+    BadMagic,
+    /// 不是 64 位小端序的 RISC-V 可执行文件
+    Unsupported,
+    /// 段的偏移或大小超出文件，或地址不在用户地址空间中
+    InvalidSegment,
+    /// 两个段的地址范围重叠
+    OverlappingSegments,
+    /// 没有可加载的段
+    NoLoadableSegment,
+    /// 入口不在可执行的段中
+    InvalidEntry,
+    /// 没有足够的页帧装入文件
+    OutOfMemory,
+}
+
+fn le16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn le32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn le64(data: &[u8], offset: usize) -> Option<usize> {
+    data.get(offset..offset + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+/// 可加载的段
+#[derive(Clone, Copy)]
+pub struct Segment<'a> {
+    pub vaddr: usize,
+    /// 段在内存中的大小，超出 `data` 的部分为 0
+    pub mem_size: usize,
+    /// 段在文件中的内容
+    pub data: &'a [u8],
+    /// `PF_R`/`PF_W`/`PF_X` 的组合
+    pub flags: u32,
+}
+
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    entry: usize,
+    ph_offset: usize,
+    ph_num: usize,
+}
+
+impl<'a> ElfFile<'a> {
+    /// 检查 ELF 头与程序头表
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if &data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64
+            || data[5] != ELFDATA2LSB
+            || data[6] != EV_CURRENT
+            || le16(data, 16) != Some(ET_EXEC)
+            || le16(data, 18) != Some(EM_RISCV)
+        {
+            return Err(ElfError::Unsupported);
+        }
+        let entry = le64(data, 24).ok_or(ElfError::Truncated)?;
+        let ph_offset = le64(data, 32).ok_or(ElfError::Truncated)?;
+        let ph_size = le16(data, 54).ok_or(ElfError::Truncated)? as usize;
+        let ph_num = le16(data, 56).ok_or(ElfError::Truncated)? as usize;
+        if ph_num != 0 && ph_size != PHDR_SIZE {
+            return Err(ElfError::Unsupported);
+        }
+        let ph_end = ph_num
+            .checked_mul(PHDR_SIZE)
+            .and_then(|size| size.checked_add(ph_offset))
+            .ok_or(ElfError::Truncated)?;
+        if ph_end > data.len() {
+            return Err(ElfError::Truncated);
+        }
+        Ok(Self {
+            data,
+            entry,
+            ph_offset,
+            ph_num,
+        })
+    }
+
+    /// 入口地址
+    pub fn entry(&self) -> usize {
+        self.entry
+    }
+
+    /// 第 `index` 个程序头，不是 `PT_LOAD` 时返回 Ok(None)
+    fn segment(&self, index: usize) -> Result<Option<Segment<'a>>, ElfError> {
+        let ph = self.ph_offset + index * PHDR_SIZE;
+        let data = self.data;
+        let read64 = |offset| le64(data, ph + offset).ok_or(ElfError::Truncated);
+        if le32(data, ph).ok_or(ElfError::Truncated)? != PT_LOAD {
+            return Ok(None);
+        }
+        let flags = le32(data, ph + 4).ok_or(ElfError::Truncated)?;
+        let (offset, vaddr) = (read64(8)?, read64(16)?);
+        let (file_size, mem_size) = (read64(32)?, read64(40)?);
+        if file_size > mem_size || vaddr.checked_add(mem_size).is_none() {
+            return Err(ElfError::InvalidSegment);
+        }
+        let data = offset
+            .checked_add(file_size)
+            .and_then(|end| data.get(offset..end))
+            .ok_or(ElfError::InvalidSegment)?;
+        Ok(Some(Segment {
+            vaddr,
+            mem_size,
+            data,
+            flags,
+        }))
+    }
+
+    /// 所有可加载的段，格式错误的段返回 Err
+    pub fn segments(&self) -> impl Iterator<Item = Result<Segment<'a>, ElfError>> + '_ {
+        (0..self.ph_num).filter_map(move |index| self.segment(index).transpose())
+    }
+}
+
+/// 构造只有 ELF 头与程序头表的可执行文件，`segments` 为 (flags, vaddr, file_size, mem_size)，
+/// 段的内容都从文件开头读取
+#[allow(unused)]
+fn build_test_elf(entry: usize, segments: &[(u32, usize, usize, usize)]) -> Vec<u8> {
+    let mut data = vec![0u8; EHDR_SIZE + segments.len() * PHDR_SIZE];
+    data[0..4].copy_from_slice(ELF_MAGIC);
+    data[4] = ELFCLASS64;
+    data[5] = ELFDATA2LSB;
+    data[6] = EV_CURRENT;
+    data[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+    data[18..20].copy_from_slice(&EM_RISCV.to_le_bytes());
+    data[24..32].copy_from_slice(&(entry as u64).to_le_bytes());
+    data[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+    data[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    data[56..58].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+    for (index, &(flags, vaddr, file_size, mem_size)) in segments.iter().enumerate() {
+        let ph = &mut data[EHDR_SIZE + index * PHDR_SIZE..][..PHDR_SIZE];
+        ph[0..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        ph[4..8].copy_from_slice(&flags.to_le_bytes());
+        ph[16..24].copy_from_slice(&(vaddr as u64).to_le_bytes());
+        ph[32..40].copy_from_slice(&(file_size as u64).to_le_bytes());
+        ph[40..48].copy_from_slice(&(mem_size as u64).to_le_bytes());
+    }
+    data
+}
+
+/// 格式错误的文件应由 `MemorySet::from_elf` 返回错误而不是 panic
+#[allow(unused)]
+pub fn elf_loader_test() {
+    let text = (PF_R | PF_X, 0x10000, 0x40, 0x1000);
+    let data = (PF_R | PF_W, 0x20000, 0x40, 0x1000);
+
+    let elf = build_test_elf(0x10000, &[text, data]);
+    assert_eq!(
+        MemorySet::from_elf(&elf[..EHDR_SIZE / 2]).err(),
+        Some(ElfError::Truncated)
+    );
+    assert_eq!(
+        MemorySet::from_elf(&elf[..elf.len() - 1]).err(),
+        Some(ElfError::Truncated)
+    );
+
+    let overlapping = (PF_R | PF_W, 0x10800, 0x40, 0x1000);
+    let elf = build_test_elf(0x10000, &[text, overlapping]);
+    assert_eq!(
+        MemorySet::from_elf(&elf).err(),
+        Some(ElfError::OverlappingSegments)
+    );
+
+    let oversized = (PF_R | PF_W, 0x20000, 0x40, 0x20);
+    let elf = build_test_elf(0x10000, &[text, oversized]);
+    assert_eq!(
+        MemorySet::from_elf(&elf).err(),
+        Some(ElfError::InvalidSegment)
+    );
+
+    let elf = build_test_elf(0x20000, &[text, data]);
+    assert_eq!(
+        MemorySet::from_elf(&elf).err(),
+        Some(ElfError::InvalidEntry)
+    );
+    let elf = build_test_elf(0x11000, &[text, data]);
+    assert_eq!(
+        MemorySet::from_elf(&elf).err(),
+        Some(ElfError::InvalidEntry)
+    );
+    info!("elf_loader_test passed!");
+}
@@ -1,16 +1,35 @@
+//! 物理页帧分配器
+//!
+//! 使用伙伴系统管理 `ekernel` 之后的物理内存，可以分配 2^order 个连续的物理页，
+//! 起始页号按 2^order 对齐。空闲块以双向链表串起，链表指针存放在空闲页自身中；
+//! 每个页帧一个字节的元数据放在可分配区域之前，不使用堆。
+//! 分配失败时先让内核堆归还缓存的空闲页，再重试一次
 extern crate alloc;
-use super::address::{PA, PPN, VA};
-use crate::arch::config::{KERNEL_MAP_OFFSET, PAGE_SIZE, PAGE_SIZE_BITS};
-use crate::{arch::config::MEMORY_END, arch::config::MEMORY_START, console::print};
+use super::address::{PAGE_SIZE, PAGE_SIZE_BITS, PPN, VA, VPN};
+use super::heap_allocator;
+use crate::arch::interface::{Interrupt, Machine};
+use crate::arch::{InterruptImpl, MachineImpl};
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::fmt::{self, Debug, Formatter};
 use lazy_static::*;
 use spin::Mutex;
+
+/// 一次最多分配 2^MAX_ORDER 个连续页帧
+pub const MAX_ORDER: usize = 10;
+
 trait FrameAllocator {
     fn new() -> Self;
-    fn alloc(&mut self) -> Option<FrameTracker>;
-    fn dealloc(&mut self, ft: &Frame);
+    /// 分配一个页帧，不在分配器的锁内创建 `FrameTracker`，避免分配器与堆互相等待
+    fn alloc(&mut self) -> Option<PPN> {
+        self.alloc_contiguous(0)
+    }
+    /// 分配 2^order 个连续的页帧，返回按 2^order 对齐的起始页号
+    fn alloc_contiguous(&mut self, order: usize) -> Option<PPN>;
+    fn dealloc(&mut self, ft: &Frame) {
+        self.dealloc_contiguous(ft.ppn, 0);
+    }
+    fn dealloc_contiguous(&mut self, ppn: PPN, order: usize);
 }
 pub struct Frame {
     // TODO 去掉 pub
@@ -35,86 +54,273 @@ impl Debug for Frame {
 }
 impl Drop for Frame {
     fn drop(&mut self) {
-        FRAME_ALLOCATOR.lock().dealloc(self);
+        with_allocator(|allocator| allocator.dealloc(self));
     }
 }
 pub type FrameTracker = Arc<Frame>;
 
-pub struct StackFrameAllocator {
-    current: usize, //空闲内存的起始物理页号
-    end: usize,     //空闲内存的结束物理页号
-    recycled: Vec<usize>,
+/// 2^order 个连续的页帧，drop 时整体回收
+pub struct ContiguousFrames {
+    ppn: PPN,
+    order: usize,
 }
 
-impl FrameAllocator for StackFrameAllocator {
-    fn new() -> Self {
-        Self {
-            current: 0,
-            end: 0,
-            recycled: Vec::new(),
-        }
+impl ContiguousFrames {
+    /// 起始物理页号
+    pub fn ppn(&self) -> PPN {
+        self.ppn
     }
 
-    fn alloc(&mut self) -> Option<FrameTracker> {
-        if let Some(ppn) = self.recycled.pop() {
-            // println!("a");
-            Some(Arc::new(Frame::new(ppn.into())))
-        } else {
-            if self.current == self.end {
-                // println!("b");
-                None
-            } else {
-                self.current += 1;
-                // println!(
-                //     "frame{:#?}",
-                //     Some(Arc::new(Frame::new((self.current - 1).into())))
-
-                // );
-                // println!("c");
-                Some(Arc::new(Frame::new((self.current - 1).into())))
-            }
-        }
+    pub fn order(&self) -> usize {
+        self.order
     }
 
-    fn dealloc(&mut self, ft: &Frame) {
-        let ppn = ft.ppn.into();
+    /// 页帧数
+    pub fn pages(&self) -> usize {
+        1 << self.order
+    }
 
-        if ppn >= self.current || self.recycled.iter().find(|&v| *v == ppn).is_some() {
-            panic!("Frame ppn={:#x} has not been allocated!", ppn);
-        }
+    /// 起始页帧在内核中的虚拟地址
+    pub fn va(&self) -> VA {
+        frame_va(self.ppn.0).into()
+    }
+}
+
+impl Debug for ContiguousFrames {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!(
+            "Frames:PPN=[{:#x}, {:#x})",
+            self.ppn.0,
+            self.ppn.0 + self.pages()
+        ))
+    }
+}
 
-        self.recycled.push(ppn);
+impl Drop for ContiguousFrames {
+    fn drop(&mut self) {
+        frame_dealloc_contiguous(self.ppn, self.order);
     }
 }
 
-impl StackFrameAllocator {
+/// 空链表
+const NONE: usize = usize::MAX;
+/// 元数据：空闲块的第一页，低位为阶数
+const META_FREE: u8 = 0x80;
+/// 元数据：已分配块的第一页，低位为阶数
+const META_ALLOCATED: u8 = 0x40;
+
+/// 空闲块第一页开头的链表指针，值为物理页号
+#[repr(C)]
+struct FreeLink {
+    prev: usize,
+    next: usize,
+}
+
+/// 物理页号在内核线性映射中的虚拟地址
+fn frame_va(ppn: usize) -> usize {
+    VPN::from(PPN(ppn)).0 << PAGE_SIZE_BITS
+}
+
+fn link(ppn: usize) -> &'static mut FreeLink {
+    unsafe { &mut *(frame_va(ppn) as *mut FreeLink) }
+}
+
+pub struct BuddyFrameAllocator {
+    /// 可分配区域的起始物理页号
+    base: usize,
+    /// 可分配区域的结束物理页号
+    end: usize,
+    /// 每个页帧的元数据，下标为 ppn - base，不是块的第一页时为 0
+    meta: &'static mut [u8],
+    /// 各阶空闲链表的表头
+    free_lists: [usize; MAX_ORDER + 1],
+    /// 空闲页帧数
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
     pub fn init(&mut self, c: PPN, e: PPN) {
-        self.current = c.0;
+        // 元数据放在区域开头
+        let meta_pages = (e.0 - c.0 + PAGE_SIZE) / (PAGE_SIZE + 1);
+        self.base = c.0 + meta_pages;
         self.end = e.0;
-        println!(
+        self.meta = unsafe {
+            core::slice::from_raw_parts_mut(frame_va(c.0) as *mut u8, self.end - self.base)
+        };
+        self.meta.fill(0);
+        // 将区域切分为尽可能大的对齐块
+        let mut ppn = self.base;
+        while ppn < self.end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| ppn % (1 << order) == 0 && ppn + (1 << order) <= self.end)
+                .unwrap();
+            self.push(ppn, order);
+            ppn += 1 << order;
+        }
+        self.free_frames = self.end - self.base;
+        info!(
             "last {} Physical Frames: [{:#x}, {:#x}]",
-            self.end - self.current,
-            self.current,
+            self.end - self.base,
+            self.base,
             self.end
         );
     }
+
+    /// 空闲页帧数
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    fn push(&mut self, ppn: usize, order: usize) {
+        let head = self.free_lists[order];
+        *link(ppn) = FreeLink {
+            prev: NONE,
+            next: head,
+        };
+        if head != NONE {
+            link(head).prev = ppn;
+        }
+        self.free_lists[order] = ppn;
+        self.meta[ppn - self.base] = META_FREE | order as u8;
+    }
+
+    fn remove(&mut self, ppn: usize, order: usize) {
+        let FreeLink { prev, next } = *link(ppn);
+        if prev == NONE {
+            self.free_lists[order] = next;
+        } else {
+            link(prev).next = next;
+        }
+        if next != NONE {
+            link(next).prev = prev;
+        }
+        self.meta[ppn - self.base] = 0;
+    }
+
+    /// `ppn` 开始的 2^order 页是否为一个空闲块
+    fn is_free_block(&self, ppn: usize, order: usize) -> bool {
+        ppn >= self.base
+            && ppn + (1 << order) <= self.end
+            && self.meta[ppn - self.base] == META_FREE | order as u8
+    }
+}
+
+impl FrameAllocator for BuddyFrameAllocator {
+    fn new() -> Self {
+        Self {
+            base: 0,
+            end: 0,
+            meta: &mut [],
+            free_lists: [NONE; MAX_ORDER + 1],
+            free_frames: 0,
+        }
+    }
+
+    fn alloc_contiguous(&mut self, order: usize) -> Option<PPN> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let mut current = (order..=MAX_ORDER).find(|&order| self.free_lists[order] != NONE)?;
+        let ppn = self.free_lists[current];
+        self.remove(ppn, current);
+        // 拆分出的后一半放回低一阶的链表
+        while current > order {
+            current -= 1;
+            self.push(ppn + (1 << current), current);
+        }
+        self.meta[ppn - self.base] = META_ALLOCATED | order as u8;
+        self.free_frames -= 1 << order;
+        Some(PPN(ppn))
+    }
+
+    fn dealloc_contiguous(&mut self, ppn: PPN, order: usize) {
+        let mut ppn = ppn.0;
+        if ppn < self.base
+            || ppn >= self.end
+            || self.meta[ppn - self.base] != META_ALLOCATED | order as u8
+        {
+            panic!(
+                "Frame ppn={:#x} order={} has not been allocated!",
+                ppn, order
+            );
+        }
+        self.meta[ppn - self.base] = 0;
+        self.free_frames += 1 << order;
+        // 伙伴空闲时合并，每阶只需检查一次
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = ppn ^ (1 << order);
+            if !self.is_free_block(buddy, order) {
+                break;
+            }
+            self.remove(buddy, order);
+            ppn = ppn.min(buddy);
+            order += 1;
+        }
+        self.push(ppn, order);
+    }
 }
 
-type FrameAllocatorImpl = StackFrameAllocator;
+type FrameAllocatorImpl = BuddyFrameAllocator;
 lazy_static! {
     pub static ref FRAME_ALLOCATOR: Mutex<FrameAllocatorImpl> =
         Mutex::new(FrameAllocatorImpl::new());
 }
 
+/// 在关中断的情况下访问分配器，堆在中断上下文中也会分配和回收页帧
+fn with_allocator<T>(f: impl FnOnce(&mut FrameAllocatorImpl) -> T) -> T {
+    unsafe {
+        let enabled = InterruptImpl::disable();
+        let result = f(&mut FRAME_ALLOCATOR.lock());
+        InterruptImpl::restore(enabled);
+        result
+    }
+}
+
+/// 分配 2^order 个连续的页帧，失败时不回收堆的缓存。
+/// 供持有堆的锁时使用，分配到的页帧须由 `frame_dealloc_contiguous` 回收
+pub fn frame_alloc_no_reclaim(order: usize) -> Option<PPN> {
+    with_allocator(|allocator| allocator.alloc_contiguous(order))
+}
+
+/// 分配失败时回收堆缓存的空闲页后重试
+fn alloc_or_reclaim(order: usize) -> Option<PPN> {
+    frame_alloc_no_reclaim(order).or_else(|| {
+        if heap_allocator::reclaim() == 0 {
+            return None;
+        }
+        frame_alloc_no_reclaim(order)
+    })
+}
+
+/// 没有空闲的页帧
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfMemory;
+
 pub fn frame_alloc() -> Option<FrameTracker> {
-    // println!("enter frame_alloc!");
-    FRAME_ALLOCATOR.lock().alloc()
+    let ppn = alloc_or_reclaim(0)?;
+    Some(Arc::new(Frame::new(ppn)))
+}
 
-    // .map(|ppn| FrameTracker::new(ppn))
+/// 分配 2^order 个连续的页帧，起始页号按 2^order 对齐
+pub fn frame_alloc_contiguous(order: usize) -> Option<ContiguousFrames> {
+    let ppn = alloc_or_reclaim(order)?;
+    Some(ContiguousFrames { ppn, order })
 }
 
 pub fn frame_dealloc(ft: &Frame) {
-    FRAME_ALLOCATOR.lock().dealloc(ft);
+    with_allocator(|allocator| allocator.dealloc(ft));
+}
+
+/// 回收 `frame_alloc_contiguous` 分配、之后被 `mem::forget` 的页帧
+pub fn frame_dealloc_contiguous(ppn: PPN, order: usize) {
+    with_allocator(|allocator| allocator.dealloc_contiguous(ppn, order));
+}
+
+/// 空闲页帧数
+pub fn free_frames() -> usize {
+    with_allocator(|allocator| allocator.free_frames())
 }
 
 #[allow(unused)]
@@ -124,20 +330,28 @@ pub fn frame_allocator_test() {
     for i in 0..5 {
         let frame = frame_alloc().unwrap();
 
-        println!("{:?}", frame);
+        debug!("{:?}", frame);
         v.push(frame);
-        println!("{}", i);
+        debug!("{}", i);
     }
 
     v.clear();
     for i in 0..5 {
         let frame = frame_alloc().unwrap();
-        println!("{:?}", frame);
+        debug!("{:?}", frame);
         v.push(frame);
     }
 
     drop(v);
-    println!("frameallocator_test passed!");
+
+    let free = free_frames();
+    for order in [0, 3, MAX_ORDER] {
+        let frames = frame_alloc_contiguous(order).unwrap();
+        debug!("{:?}", frames);
+        assert_eq!(frames.ppn().0 % frames.pages(), 0);
+    }
+    assert_eq!(free_frames(), free);
+    info!("frameallocator_test passed!");
 }
 
 /// init frame allocator
@@ -146,13 +360,15 @@ pub fn init_allocator() {
         fn ekernel();
     }
 
-    FRAME_ALLOCATOR.lock().init(
-        // VA::from(ekernel as usize + KERNEL_MAP_OFFSET).ceil().into(),
-        // VA::from(ekernel as usize + KERNEL_MAP_OFFSET + MEMORY_SIZE).floor().into()
-        VA::from(ekernel as usize).ceil().into(),
-        VA::from(MEMORY_END).floor().into(),
-    );
+    with_allocator(|allocator| {
+        allocator.init(
+            // VA::from(ekernel as usize + KERNEL_MAP_OFFSET).ceil().into(),
+            // VA::from(ekernel as usize + KERNEL_MAP_OFFSET + MEMORY_SIZE).floor().into()
+            VA::from(ekernel as usize).ceil().into(),
+            VA::from(MachineImpl::memory_end()).floor().into(),
+        )
+    });
 
     //TODO debug 加了print语句后不触发page fault bug
-    println!("success init frame allocator!");
+    info!("success init frame allocator!");
 }
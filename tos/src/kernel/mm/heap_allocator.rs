@@ -1,24 +1,460 @@
-use crate::arch::config::KERNEL_HEAP_SIZE;
-use buddy_system_allocator::LockedHeap;
+//! 内核堆
+//!
+//! 不超过 `MAX_SLAB_SIZE` 的分配按 2 的幂划分大小类，每类一个 slab 缓存。
+//! slab 占一页，页首为 `Slab` 头，其余部分切分为等大的对象，空闲对象以单链表串起；
+//! 对象大小为 2 的幂且不小于对齐，因此对象按自身大小对齐。释放时由地址向下取整到页得到 slab。
+//! slab 页先取自启动时的 `HEAP_SPACE`，用完后从帧分配器分配，通过线性映射访问；
+//! slab 全部空闲时归还，来自帧分配器的空闲页只缓存 `MAX_CACHED_PAGES` 页，其余还给帧分配器，
+//! 帧分配器分配失败时会调用 `reclaim` 收回缓存的页。
+//! 更大的分配直接从帧分配器分配 2^order 页
+use super::address::{KERNEL_MAP_OFFSET, PAGE_SIZE, PAGE_SIZE_BITS, PPN};
+use super::frame_allocator::{
+    frame_alloc_contiguous, frame_alloc_no_reclaim, frame_dealloc_contiguous,
+};
+use crate::arch::interface::Interrupt;
+use crate::arch::InterruptImpl;
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// 最小的对象大小，须能放下空闲链表指针
+const MIN_SLAB_SIZE: usize = 8;
+/// 由 slab 分配的最大对象
+const MAX_SLAB_SIZE: usize = 1024;
+/// 大小类的个数，8, 16, ..., 1024
+const NUM_CACHES: usize = 8;
+/// 缓存的来自帧分配器的空闲页数上限
+const MAX_CACHED_PAGES: usize = 16;
+/// 启动时的内核堆大小，帧分配器初始化之后堆按需从帧分配器扩展
+const KERNEL_HEAP_SIZE: usize = 0x4_0000;
 
-//TODO 实现自己的分配器
 #[global_allocator]
-static HEAP_ALLOCATOR: LockedHeap = LockedHeap::empty();
-//TODO LockedHeap 无法在String::from()上工作
+static HEAP_ALLOCATOR: SlabAllocator = SlabAllocator::new();
+
 #[repr(align(4096))]
 pub struct HeapSpace(pub [u8; KERNEL_HEAP_SIZE]);
 static mut HEAP_SPACE: HeapSpace = HeapSpace([0; KERNEL_HEAP_SIZE]);
 
+/// 空闲对象或空闲页开头的链表指针
+struct FreeNode {
+    next: *mut FreeNode,
+}
+
+/// slab 页首的头部
+#[repr(C)]
+struct Slab {
+    /// 缓存中有空闲对象的 slab 组成的双向链表
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: *mut FreeNode,
+    /// 已分配的对象数
+    inuse: usize,
+}
+
+/// 一个大小类的统计信息
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub object_size: usize,
+    /// slab 页数
+    pub slabs: usize,
+    /// 已分配的对象数
+    pub active_objects: usize,
+    /// 所有 slab 中的对象数
+    pub total_objects: usize,
+    /// 累计的分配与释放次数
+    pub allocs: usize,
+    pub frees: usize,
+}
+
+struct SlabCache {
+    /// 有空闲对象的 slab
+    partial: *mut Slab,
+    stats: CacheStats,
+}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> Self {
+        Self {
+            partial: null_mut(),
+            stats: CacheStats {
+                object_size,
+                slabs: 0,
+                active_objects: 0,
+                total_objects: 0,
+                allocs: 0,
+                frees: 0,
+            },
+        }
+    }
+
+    /// 第一个对象在页内的偏移，跳过头部并按对象大小对齐
+    fn first_object(&self) -> usize {
+        let size = self.stats.object_size;
+        (size_of::<Slab>() + size - 1) / size * size
+    }
+
+    fn objects_per_slab(&self) -> usize {
+        (PAGE_SIZE - self.first_object()) / self.stats.object_size
+    }
+
+    fn push_partial(&mut self, slab: *mut Slab) {
+        unsafe {
+            (*slab).prev = null_mut();
+            (*slab).next = self.partial;
+            if !self.partial.is_null() {
+                (*self.partial).prev = slab;
+            }
+        }
+        self.partial = slab;
+    }
+
+    fn remove_partial(&mut self, slab: *mut Slab) {
+        unsafe {
+            let (prev, next) = ((*slab).prev, (*slab).next);
+            if prev.is_null() {
+                self.partial = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+    }
+
+    /// 将一页切分为对象，作为新的 slab 加入缓存
+    fn add_slab(&mut self, page: *mut u8) {
+        let slab = page as *mut Slab;
+        let size = self.stats.object_size;
+        let count = self.objects_per_slab();
+        unsafe {
+            let mut free = null_mut();
+            for i in (0..count).rev() {
+                let node = page.add(self.first_object() + i * size) as *mut FreeNode;
+                (*node).next = free;
+                free = node;
+            }
+            (*slab).free = free;
+            (*slab).inuse = 0;
+        }
+        self.push_partial(slab);
+        self.stats.slabs += 1;
+        self.stats.total_objects += count;
+    }
+
+    /// 从有空闲对象的 slab 中分配，没有时返回空指针
+    fn alloc(&mut self) -> *mut u8 {
+        let slab = self.partial;
+        if slab.is_null() {
+            return null_mut();
+        }
+        unsafe {
+            let node = (*slab).free;
+            (*slab).free = (*node).next;
+            (*slab).inuse += 1;
+            if (*slab).free.is_null() {
+                self.remove_partial(slab);
+            }
+            self.stats.allocs += 1;
+            self.stats.active_objects += 1;
+            node as *mut u8
+        }
+    }
+
+    /// 释放对象，slab 全部空闲时返回该页
+    fn dealloc(&mut self, ptr: *mut u8) -> Option<*mut u8> {
+        let slab = (ptr as usize & !(PAGE_SIZE - 1)) as *mut Slab;
+        self.stats.frees += 1;
+        self.stats.active_objects -= 1;
+        unsafe {
+            let node = ptr as *mut FreeNode;
+            let was_full = (*slab).free.is_null();
+            (*node).next = (*slab).free;
+            (*slab).free = node;
+            (*slab).inuse -= 1;
+            if was_full {
+                self.push_partial(slab);
+            }
+            if (*slab).inuse == 0 {
+                self.remove_partial(slab);
+                self.stats.slabs -= 1;
+                self.stats.total_objects -= self.objects_per_slab();
+                return Some(slab as *mut u8);
+            }
+        }
+        None
+    }
+}
+
+/// 页帧在线性映射中的地址
+fn page_va(ppn: PPN) -> *mut u8 {
+    ((ppn.0 << PAGE_SIZE_BITS) + KERNEL_MAP_OFFSET) as *mut u8
+}
+
+fn page_ppn(page: *mut u8) -> PPN {
+    PPN((page as usize - KERNEL_MAP_OFFSET) >> PAGE_SIZE_BITS)
+}
+
+/// 页是否属于 `HEAP_SPACE`，这些页不能交给帧分配器
+fn is_bootstrap_page(page: *mut u8) -> bool {
+    let start = unsafe { HEAP_SPACE.0.as_ptr() } as usize;
+    (start..start + KERNEL_HEAP_SIZE).contains(&(page as usize))
+}
+
+/// 空闲页组成的单链表
+struct PageList {
+    head: *mut FreeNode,
+    len: usize,
+}
+
+impl PageList {
+    const fn new() -> Self {
+        Self {
+            head: null_mut(),
+            len: 0,
+        }
+    }
+
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.head.is_null() {
+            return None;
+        }
+        let page = self.head;
+        unsafe { self.head = (*page).next };
+        self.len -= 1;
+        Some(page as *mut u8)
+    }
+
+    fn push(&mut self, page: *mut u8) {
+        let node = page as *mut FreeNode;
+        unsafe { (*node).next = self.head };
+        self.head = node;
+        self.len += 1;
+    }
+}
+
+/// slab 页的来源
+struct PagePool {
+    /// `HEAP_SPACE` 中的空闲页
+    bootstrap: PageList,
+    /// 来自帧分配器的空闲页
+    cached: PageList,
+    /// 从帧分配器分配、尚未归还的页数，包括缓存的页
+    frame_pages: usize,
+}
+
+impl PagePool {
+    /// 优先使用 `HEAP_SPACE`，用完后从帧分配器分配
+    fn alloc(&mut self) -> Option<*mut u8> {
+        if let Some(page) = self.bootstrap.pop().or_else(|| self.cached.pop()) {
+            return Some(page);
+        }
+        // 持有堆的锁，不能让帧分配器回调 `reclaim`
+        let ppn = frame_alloc_no_reclaim(0)?;
+        self.frame_pages += 1;
+        Some(page_va(ppn))
+    }
+
+    fn dealloc(&mut self, page: *mut u8) {
+        if is_bootstrap_page(page) {
+            self.bootstrap.push(page);
+        } else if self.cached.len < MAX_CACHED_PAGES {
+            self.cached.push(page);
+        } else {
+            self.release(page);
+        }
+    }
+
+    /// 将页还给帧分配器
+    fn release(&mut self, page: *mut u8) {
+        frame_dealloc_contiguous(page_ppn(page), 0);
+        self.frame_pages -= 1;
+    }
+
+    /// 将缓存的页全部还给帧分配器，返回归还的页数
+    fn shrink(&mut self) -> usize {
+        let count = self.cached.len;
+        while let Some(page) = self.cached.pop() {
+            self.release(page);
+        }
+        count
+    }
+
+    fn free_pages(&self) -> usize {
+        self.bootstrap.len + self.cached.len
+    }
+}
+
+struct Heap {
+    caches: [SlabCache; NUM_CACHES],
+    pages: PagePool,
+}
+
+// 堆中的指针只在持有锁时访问
+unsafe impl Send for Heap {}
+
+pub struct SlabAllocator {
+    heap: Mutex<Heap>,
+    /// 直接从帧分配器分配的页数
+    large_pages: AtomicUsize,
+}
+
+impl SlabAllocator {
+    const fn new() -> Self {
+        Self {
+            heap: Mutex::new(Heap {
+                caches: [
+                    SlabCache::new(8),
+                    SlabCache::new(16),
+                    SlabCache::new(32),
+                    SlabCache::new(64),
+                    SlabCache::new(128),
+                    SlabCache::new(256),
+                    SlabCache::new(512),
+                    SlabCache::new(1024),
+                ],
+                pages: PagePool {
+                    bootstrap: PageList::new(),
+                    cached: PageList::new(),
+                    frame_pages: 0,
+                },
+            }),
+            large_pages: AtomicUsize::new(0),
+        }
+    }
+
+    /// 在关中断的情况下访问堆，中断处理中也会分配和释放内存
+    fn with_heap<T>(&self, f: impl FnOnce(&mut Heap) -> T) -> T {
+        unsafe {
+            let enabled = InterruptImpl::disable();
+            let result = f(&mut self.heap.lock());
+            InterruptImpl::restore(enabled);
+            result
+        }
+    }
+}
+
+/// `layout` 对应的大小类，过大时返回 None
+fn cache_index(layout: &Layout) -> Option<usize> {
+    let size = layout
+        .size()
+        .max(layout.align())
+        .max(MIN_SLAB_SIZE)
+        .next_power_of_two();
+    if size > MAX_SLAB_SIZE {
+        return None;
+    }
+    Some((size / MIN_SLAB_SIZE).trailing_zeros() as usize)
+}
+
+/// 大块分配的阶数，同时满足大小与对齐
+fn large_order(layout: &Layout) -> usize {
+    let pages = (layout.size().max(layout.align()) + PAGE_SIZE - 1) / PAGE_SIZE;
+    pages.next_power_of_two().trailing_zeros() as usize
+}
+
+unsafe impl GlobalAlloc for SlabAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let index = match cache_index(&layout) {
+            Some(index) => index,
+            None => {
+                let order = large_order(&layout);
+                return match frame_alloc_contiguous(order) {
+                    Some(frames) => {
+                        let va = frames.va().0;
+                        core::mem::forget(frames);
+                        self.large_pages.fetch_add(1 << order, Ordering::Relaxed);
+                        va as *mut u8
+                    }
+                    None => null_mut(),
+                };
+            }
+        };
+        self.with_heap(|heap| {
+            let cache = &mut heap.caches[index];
+            if cache.partial.is_null() {
+                match heap.pages.alloc() {
+                    Some(page) => cache.add_slab(page),
+                    None => return null_mut(),
+                }
+            }
+            cache.alloc()
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let index = match cache_index(&layout) {
+            Some(index) => index,
+            None => {
+                let order = large_order(&layout);
+                frame_dealloc_contiguous(page_ppn(ptr), order);
+                self.large_pages.fetch_sub(1 << order, Ordering::Relaxed);
+                return;
+            }
+        };
+        self.with_heap(|heap| {
+            if let Some(page) = heap.caches[index].dealloc(ptr) {
+                heap.pages.dealloc(page);
+            }
+        });
+    }
+}
+
 pub fn init_heap() {
-    unsafe {
-        HEAP_ALLOCATOR
-            .lock()
-            .init(HEAP_SPACE.0.as_ptr() as usize, KERNEL_HEAP_SIZE);
+    HEAP_ALLOCATOR.with_heap(|heap| unsafe {
+        for offset in (0..KERNEL_HEAP_SIZE).step_by(PAGE_SIZE).rev() {
+            heap.pages
+                .bootstrap
+                .push(HEAP_SPACE.0.as_mut_ptr().add(offset));
+        }
+    });
+    info!("success init heap allocator!");
+}
+
+/// 将堆缓存的空闲页还给帧分配器，返回归还的页数。
+/// 帧分配器分配失败时调用，调用时不能持有帧分配器的锁
+pub fn reclaim() -> usize {
+    HEAP_ALLOCATOR.with_heap(|heap| heap.pages.shrink())
+}
+
+/// 各大小类的统计信息
+pub fn cache_stats() -> [CacheStats; NUM_CACHES] {
+    HEAP_ALLOCATOR.with_heap(|heap| {
+        let mut stats = [CacheStats::default(); NUM_CACHES];
+        for (slot, cache) in stats.iter_mut().zip(heap.caches.iter()) {
+            *slot = cache.stats;
+        }
+        stats
+    })
+}
+
+/// 输出堆的使用情况
+pub fn print_stats() {
+    let (free_pages, frame_pages) =
+        HEAP_ALLOCATOR.with_heap(|heap| (heap.pages.free_pages(), heap.pages.frame_pages));
+    info!(
+        "heap: {} free slab pages, {} slab pages from frame allocator, {} large pages",
+        free_pages,
+        frame_pages,
+        HEAP_ALLOCATOR.large_pages.load(Ordering::Relaxed)
+    );
+    for stats in cache_stats() {
+        info!(
+            "  size-{:<4} slabs {:>4} objects {:>5}/{:<5} allocs {} frees {}",
+            stats.object_size,
+            stats.slabs,
+            stats.active_objects,
+            stats.total_objects,
+            stats.allocs,
+            stats.frees
+        );
     }
-    println!("success init heap allocator!");
 }
 
 #[alloc_error_handler]
 pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
+    print_stats();
     panic!("Heap allocation error, layout = {:?}", layout);
 }
@@ -1,4 +1,5 @@
 pub mod address;
+pub mod elf;
 pub mod frame_allocator;
 pub mod heap_allocator;
 pub mod page_table;
@@ -7,11 +8,12 @@ use crate::kernel::mm::address::VARangeOrd;
 use crate::kernel::mm::page_table::kernel_page_table;
 use alloc::collections::BTreeMap;
 use core::iter::Map;
-use riscv::register::satp;
 // pub use p:KERNEL_PAGE_TABLE;
+use crate::arch::{interface::Mmu, MmuImpl};
 use space::KERNEL_SPACE;
 use space::{MapArea, MapPermission, MemorySet};
 pub fn init_mm() {
+    MmuImpl::init();
     heap_allocator::init_heap();
     // println!("success init heap allocator");
     frame_allocator::init_allocator();
@@ -45,12 +47,11 @@ pub fn init_mm() {
 }
 
 pub fn kernel_remap() {
-    println!("success");
     extern "C" {
         fn boot_stack(); //定义在src/boot/entry64.asm
         fn boot_stack_top(); //定义在src/boot/entry64.asm
     }
-    let mut memory_set = MemorySet::new();
+    let mut memory_set = MemorySet::try_new_bare().unwrap();
 
     let mut map_area = MapArea::new(
         (boot_stack as usize).into(),
@@ -64,5 +65,4 @@ pub fn kernel_remap() {
     unsafe {
         memory_set.activate();
     }
-    println!("success");
 }
@@ -1,65 +1,25 @@
-use super::address::{VARange, VARangeOrd, PPN, VA, VPN};
-use super::frame_allocator::{frame_alloc, frame_dealloc, Frame, FrameTracker};
+use super::address::{VARange, VARangeOrd, KERNEL_MAP_OFFSET, PA, PPN, VA, VPN};
+use super::frame_allocator::{frame_alloc, frame_dealloc, Frame, FrameTracker, OutOfMemory};
 use super::space::{MapArea, MapPermission, MapType};
-use crate::arch::config::{KERNEL_STACK_TOP, MEMORY_END};
+pub use crate::arch::interface::PTEFlags;
+use crate::arch::interface::{Machine, Mmu, PageTableEntry};
+use crate::arch::{MachineImpl, MmuImpl};
 use crate::console::print;
 // use crate::kernel::process::process::KERNEL_PROCESS;
 use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
-use bitflags::*;
 use core::slice::from_raw_parts_mut;
 use lazy_static::lazy_static;
-use riscv::asm::{sfence_vma, sfence_vma_all};
-use riscv::register::{satp, sscratch};
-bitflags! {
-    pub struct PTEFlags: u8 {
-        const E =      0;
-        const V = 1 << 0;
-        const R = 1 << 1;
-        const W = 1 << 2;
-        const X = 1 << 3;
-        const U = 1 << 4;
-        const G = 1 << 5;
-        const A = 1 << 6;
-        const D = 1 << 7;
-    }
-}
 impl PTEFlags {
     pub fn to_perm(&self) -> MapPermission {
-        MapPermission::from_bits(0x3c as u8 & self.bits).unwrap()
+        MapPermission::from_bits(0x3c as u8 & self.bits()).unwrap()
     }
 }
-#[derive(Copy, Clone)]
-#[repr(C)]
-pub struct PTE {
-    pub bits: usize,
-}
 
-impl PTE {
-    pub fn new(ppn: PPN, flags: PTEFlags) -> Self {
-        PTE {
-            bits: ppn.0 << 10 | flags.bits as usize,
-        }
-    }
-
-    pub fn empty() -> Self {
-        PTE { bits: 0 }
-    }
-
-    pub fn ppn(&self) -> PPN {
-        (self.bits >> 10 & ((1usize << 44) - 1)).into()
-    }
-
-    pub fn flags(&self) -> PTEFlags {
-        PTEFlags::from_bits(self.bits as u8).unwrap()
-    }
-
-    pub fn is_valid(&self) -> bool {
-        (self.flags() & PTEFlags::V) != PTEFlags::empty()
-    }
-}
+/// 页表项，编码由 `Mmu` 决定
+pub type PTE = <MmuImpl as Mmu>::Pte;
 
 #[repr(align(4096))]
 // pub struct PageTable {
@@ -73,27 +33,39 @@ pub struct PageTable {
 impl PageTable {
     ///create a new page table
     pub fn new() -> Self {
-        let frame = frame_alloc().unwrap();
+        Self::try_new().unwrap()
+    }
+
+    /// 创建空的页表，没有页帧作为根页表时返回错误
+    pub fn try_new() -> Result<Self, OutOfMemory> {
+        let frame = frame_alloc().ok_or(OutOfMemory)?;
+        VPN::from(frame.ppn).get_array::<PTE>().fill(PTE::empty());
         // println!("new page table");
-        Self {
+        Ok(Self {
             root: frame,
             frames: vec![],
-        }
+        })
     }
 
-    /// TODO 考虑页面不够的情况
-    pub fn map(&mut self, va_range: VARangeOrd, area: &mut MapArea, data: Option<&[u8]>) {
+    /// 映射区域中 `va_range` 内的页并复制数据。
+    /// 页帧不足时撤销这次已映射的页并返回错误，已分配的中间级页表保留在页表中
+    pub fn map(
+        &mut self,
+        va_range: VARangeOrd,
+        area: &mut MapArea,
+        data: Option<&[u8]>,
+    ) -> Result<(), OutOfMemory> {
+        let vpn_range = va_range.vpn_range();
+        for vpn in vpn_range.clone() {
+            if let Err(error) = area.map_one(self, vpn) {
+                for mapped in vpn_range.start..vpn {
+                    area.unmap_one(self, mapped);
+                }
+                return Err(error);
+            }
+        }
         match area.map_type {
             MapType::Linear => {
-                for vpn in va_range.vpn_range() {
-                    // println!("vpn_range:{:?}", vpn);
-                    self.map_one(vpn, vpn.into(), area.map_perm.to_pte());
-                }
-                use riscv::asm::ebreak;
-                // unsafe {
-                //     ebreak();
-                // }
-                println!("finish map_one");
                 // 线性映射的 area 是一段连续的地址，可以直接复制
                 if let Some(data) = data {
                     unsafe {
@@ -111,33 +83,23 @@ impl PageTable {
                         // println!("src_vpn_range {:x?}", src_vpn_range);
                         // println!("vpn {:x?}", va_range.vpn_range());
                         // XXX va_range.start 和 end 可能并非 4k 对齐的，导致多复制了一些数据
-                        for (vpn, src_vpn) in va_range.vpn_range().zip(src_vpn_range) {
-                            let dst_frame = frame_alloc().unwrap();
-                            self.map_one(vpn, dst_frame.ppn, area.map_perm.to_pte());
-                            VPN::from(dst_frame.ppn)
+                        for (vpn, src_vpn) in vpn_range.zip(src_vpn_range) {
+                            VPN::from(area.data_frames[&vpn].ppn)
                                 .get_array()
                                 .copy_from_slice(src_vpn.get_array::<usize>());
                             // println!("{:?}", src_vpn.get_array::<usize>());
-                            area.data_frames.insert(vpn, dst_frame);
                         }
                     }
                     // 数据长度为 0，说明是 bss 段
                     Some(_) => {
-                        for vpn in va_range.vpn_range() {
-                            let dst_frame = frame_alloc().unwrap();
-                            self.map_one(vpn, dst_frame.ppn, area.map_perm.to_pte());
-                            VPN::from(dst_frame.ppn).get_array().fill(0usize);
-                            area.data_frames.insert(vpn, dst_frame);
+                        for vpn in vpn_range {
+                            VPN::from(area.data_frames[&vpn].ppn)
+                                .get_array()
+                                .fill(0usize);
                         }
                     }
                     // 内核栈/用户栈
-                    _ => {
-                        for vpn in va_range.vpn_range() {
-                            let dst_frame = frame_alloc().unwrap();
-                            self.map_one(vpn, dst_frame.ppn, area.map_perm.to_pte());
-                            area.data_frames.insert(vpn, dst_frame);
-                        }
-                    }
+                    _ => {}
                 }
             } // MapType::Device => {
               //     for vpn in va_range.vpn_range() {
@@ -145,17 +107,41 @@ impl PageTable {
               //     }
               // }
         }
+        Ok(())
     }
     //TODO 暂时copy 后续优化
-    pub fn map_one(&mut self, vpn: VPN, ppn: PPN, flags: PTEFlags) {
-        let pte = self.find_pte_create(vpn).unwrap();
+    /// 映射一页，没有页帧作为中间级页表时返回错误
+    pub fn map_one(&mut self, vpn: VPN, ppn: PPN, flags: PTEFlags) -> Result<(), OutOfMemory> {
+        let pte = self.find_pte_create(vpn).ok_or(OutOfMemory)?;
         assert!(!pte.is_valid(), "vpn {:?} is mapped before mapping", vpn);
-        *pte = PTE::new(ppn, flags | PTEFlags::V);
+        *pte = PTE::new(ppn.0, flags | PTEFlags::V);
         // println!("map pte: {:#x}", pte.bits);
+        Ok(())
+    }
+
+    /// 将跳板页映射到 TRAMPOLINE，每个地址空间都需要映射
+    pub fn map_trampoline(&mut self) -> Result<(), OutOfMemory> {
+        extern "C" {
+            fn strampoline();
+        }
+        self.map_one(
+            VA::from(MmuImpl::TRAMPOLINE).into(),
+            PA::from(strampoline as usize - KERNEL_MAP_OFFSET).into(),
+            PTEFlags::R | PTEFlags::X,
+        )
+    }
+
+    /// 复制 `kernel` 根页表高半部分的页表项，使该页表包含内核的全部映射（含跳板页与内核栈）。
+    /// 中间级页表与内核共用，内核之后在其中添加的映射（如新的内核栈）同样可见
+    pub fn share_kernel_mappings(&mut self, kernel: &PageTable) {
+        let kernel_entries = VPN::from(kernel.root.ppn).get_array::<PTE>();
+        let half = kernel_entries.len() / 2;
+        VPN::from(self.root.ppn).get_array::<PTE>()[half..]
+            .copy_from_slice(&kernel_entries[half..]);
     }
 
     pub fn unmap(&mut self, vpn: VPN) {
-        let pte = self.find_pte_create(vpn).unwrap();
+        let pte = self.find_pte(vpn).unwrap();
         assert!(pte.is_valid(), "vpn {:?} is invalid before unmapping", vpn);
         *pte = PTE::empty();
     }
@@ -180,59 +166,50 @@ impl PageTable {
     //     }
     //     result
     // }
+    /// 查找 `vpn` 对应的页表项，中间级页表不存在时创建，没有页帧时返回 None
     fn find_pte_create(&mut self, vpn: VPN) -> Option<&mut PTE> {
-        let idxs = vpn.indexes();
+        let levels = MmuImpl::levels();
+        let idxs = vpn.indexes(levels);
         // println!("idx{:?}", idxs);
         //获取PTE
         let mut pte: &mut PTE = &mut VPN::from(self.root.ppn).get_array::<PTE>()[idxs[0]];
 
         // println!("3level: {:#x}", pte.bits);
         //
-        for &idx in &idxs[1..] {
+        for &idx in &idxs[1..levels] {
             if !pte.is_valid() {
-                let frame = frame_alloc().unwrap();
+                let frame = frame_alloc()?;
                 VPN::from(frame.ppn).get_array::<PTE>().fill(PTE::empty());
-                *pte = PTE::new(frame.ppn, PTEFlags::V);
+                *pte = PTE::new(frame.ppn.0, PTEFlags::V);
                 self.frames.push(frame);
             }
-            pte = &mut VPN::from(pte.ppn()).get_array::<PTE>()[idx];
+            pte = &mut VPN::from(PPN(pte.ppn())).get_array::<PTE>()[idx];
         }
         Some(pte)
     }
 
-    /// set satp value 1000 means SV39
-    pub fn token(&self) -> usize {
-        8usize << 60 | self.root.ppn.0
-    }
-
-    unsafe fn set_token(token: usize) {
-        llvm_asm!("csrw satp, $0" :: "r"(token) :: "volatile");
-    }
-
-    fn active_token() -> usize {
-        satp::read().bits()
+    /// 查找 `vpn` 对应的页表项，中间级页表不存在时返回 None
+    pub fn find_pte(&mut self, vpn: VPN) -> Option<&mut PTE> {
+        let levels = MmuImpl::levels();
+        let idxs = vpn.indexes(levels);
+        let mut pte: &mut PTE = &mut VPN::from(self.root.ppn).get_array::<PTE>()[idxs[0]];
+        for &idx in &idxs[1..levels] {
+            if !pte.is_valid() {
+                return None;
+            }
+            pte = &mut VPN::from(PPN(pte.ppn())).get_array::<PTE>()[idx];
+        }
+        Some(pte)
     }
 
-    fn flush_tlb() {
-        unsafe {
-            unsafe {
-                unsafe {
-                    sfence_vma_all();
-                }
-            };
-        }
+    /// 页表令牌，在 RISC-V 中即为 satp 的值
+    pub fn token(&self) -> usize {
+        MmuImpl::token(self.root.ppn.0)
     }
 
+    /// 切换到该页表
     pub unsafe fn activate(&self) {
-        println!("enter active:");
-        let old_token = Self::active_token();
-        let new_token = self.token();
-        println!("switch satp from {:#x} to {:#x}", old_token, new_token);
-        if new_token != old_token {
-            Self::set_token(new_token);
-            // 别忘了刷新 TLB!
-            Self::flush_tlb();
-        }
+        MmuImpl::activate(self.token());
     }
 }
 
@@ -243,7 +220,7 @@ impl PageTable {
 // }
 use core::{fmt::Debug, iter::Step, mem::size_of};
 pub fn kernel_page_table() -> PageTable {
-    println!("enter new kernel page table!");
+    debug!("enter new kernel page table!");
     // loop {}
     let frame = frame_alloc().unwrap();
     // use riscv::register::satp;
@@ -285,34 +262,57 @@ pub fn kernel_page_table() -> PageTable {
             PTEFlags::R | PTEFlags::W,
         ),
         (
-            (ekernel as usize).into()..MEMORY_END.into(),
+            (ekernel as usize).into()..MachineImpl::memory_end().into(),
             PTEFlags::R | PTEFlags::W,
         ),
     ];
 
     for area in areas {
-        page_table.map(
-            VARangeOrd((area.0).clone()),
-            &mut MapArea {
-                //TODO 精简MapArea
-                vpn_range: VARangeOrd((area.0).clone()).vpn_range(),
-                data_frames: BTreeMap::new(),
-                map_type: MapType::Linear,
-                map_perm: area.1.to_perm(),
-            },
-            None,
-        );
+        page_table
+            .map(
+                VARangeOrd((area.0).clone()),
+                &mut MapArea {
+                    //TODO 精简MapArea
+                    vpn_range: VARangeOrd((area.0).clone()).vpn_range(),
+                    data_frames: BTreeMap::new(),
+                    map_type: MapType::Linear,
+                    map_perm: area.1.to_perm(),
+                },
+                None,
+            )
+            .unwrap();
+    }
+    page_table.map_trampoline().unwrap();
+    // 设备的 MMIO 区域
+    for (base, size) in MachineImpl::mmio_regions() {
+        let va_range: VARange =
+            (base + KERNEL_MAP_OFFSET).into()..(base + size + KERNEL_MAP_OFFSET).into();
+        page_table
+            .map(
+                VARangeOrd(va_range.clone()),
+                &mut MapArea {
+                    vpn_range: VARangeOrd(va_range).vpn_range(),
+                    data_frames: BTreeMap::new(),
+                    map_type: MapType::Linear,
+                    map_perm: MapPermission::R | MapPermission::W,
+                },
+                None,
+            )
+            .unwrap();
     }
-    println!("{:#x}", KERNEL_STACK_TOP);
-    let vpn = VA(KERNEL_STACK_TOP).floor().indexes()[0];
-    println!("{}", vpn);
+    trace!("{:#x}", MmuImpl::KERNEL_STACK_TOP);
+    let vpn = VA(MmuImpl::KERNEL_STACK_TOP)
+        .floor()
+        .indexes(MmuImpl::levels())[0];
+    trace!("{}", vpn);
     let pte: &mut PTE = &mut VPN::from(page_table.root.ppn).get_array::<PTE>()[vpn];
-    println!("{:#x}", pte.bits);
-    let frame = frame_alloc().unwrap();
-    VPN::from(frame.ppn).get_array::<PTE>().fill(PTE::empty());
-    *pte = PTE::new(frame.ppn, PTEFlags::V);
-    page_table.frames.push(frame);
-    println!("sucess init kernel page table");
-    println!("sscrach: {:#x}", sscratch::read());
+    // Sv48 中内核栈与内核代码共用根页表的最后一项，此时已经创建
+    if !pte.is_valid() {
+        let frame = frame_alloc().unwrap();
+        VPN::from(frame.ppn).get_array::<PTE>().fill(PTE::empty());
+        *pte = PTE::new(frame.ppn.0, PTEFlags::V);
+        page_table.frames.push(frame);
+    }
+    info!("success init kernel page table");
     page_table
 }
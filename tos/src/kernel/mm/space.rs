@@ -1,16 +1,25 @@
-use super::address::{VARange, VPNRange, PA, PPN, VA, VPN};
-use super::frame_allocator::{frame_alloc, FrameTracker};
-use super::page_table::{PTEFlags, PageTable};
-use crate::arch::config::{MEMORY_END, TRAMPOLINE};
+use super::address::{
+    VARange, VPNRange, KERNEL_MAP_OFFSET, PA, PAGE_SIZE, PAGE_SIZE_BITS, PPN, VA, VPN,
+};
+use super::elf::{ElfError, ElfFile, Segment, PF_R, PF_W, PF_X};
+use super::frame_allocator::{frame_alloc, FrameTracker, OutOfMemory};
+use super::page_table::{PTEFlags, PageTable, PTE};
+use crate::arch::interface::{Machine, Mmu, PageTableEntry};
+use crate::arch::{MachineImpl, MmuImpl};
 use crate::console::print;
 use crate::kernel::mm::address::VARangeOrd;
+use crate::kernel::process::process::KERNEL_PROCESS;
 use _core::iter::Map;
 use alloc::collections::BTreeMap;
 use alloc::vec;
 use alloc::vec::Vec;
 use bitflags::*;
 use lazy_static::*;
-use riscv::register::satp;
+/// 用户栈大小
+const USER_STACK_SIZE: usize = 1 << 13;
+/// 用户栈位于用户地址空间的最高处
+const USER_STACK_TOP: usize = MmuImpl::USER_SPACE_END;
+
 #[derive(Clone, Copy)]
 pub enum MapType {
     /// 线性映射
@@ -37,6 +46,56 @@ impl MapPermission {
     pub fn to_pte(&self) -> PTEFlags {
         PTEFlags::from_bits(self.bits).unwrap()
     }
+
+    /// ELF 段的权限，RISC-V 不允许只写的页，可写的段同时可读
+    fn from_elf_flags(flags: u32) -> Self {
+        let mut perm = MapPermission::U;
+        if flags & (PF_R | PF_W) != 0 {
+            perm |= MapPermission::R;
+        }
+        if flags & PF_W != 0 {
+            perm |= MapPermission::W;
+        }
+        if flags & PF_X != 0 {
+            perm |= MapPermission::X;
+        }
+        perm
+    }
+}
+
+/// 引发缺页异常的访问类型
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    /// 该访问需要的权限，`user` 表示来自用户态
+    fn required(&self, user: bool) -> MapPermission {
+        let perm = match self {
+            Access::Read => MapPermission::R,
+            Access::Write => MapPermission::W,
+            Access::Execute => MapPermission::X,
+        };
+        if user {
+            perm | MapPermission::U
+        } else {
+            perm
+        }
+    }
+}
+
+/// 无法处理的缺页异常
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageFaultError {
+    /// 地址不属于任何区域
+    Unmapped,
+    /// 区域的权限不允许该访问
+    PermissionDenied,
+    /// 没有空闲的页帧
+    OutOfMemory,
 }
 pub struct MapArea {
     pub vpn_range: VPNRange,
@@ -52,24 +111,48 @@ pub struct MemorySet {
 
 impl MemorySet {
     pub fn new() -> Self {
-        println!("new!");
-        Self {
-            page_table: PageTable::new(),
-            areas: BTreeMap::<VARangeOrd, MapArea>::new(),
+        Self::try_new().unwrap()
+    }
+
+    /// 创建用户地址空间，页帧不足时返回错误。
+    /// 用户与内核共用页表时复制内核的映射（含跳板页与内核栈）；
+    /// 使用不同页表（`separate-page-table` 特性）时只映射跳板页，线程的内核栈由 `map_kernel_stack` 映射
+    pub fn try_new() -> Result<Self, OutOfMemory> {
+        if cfg!(feature = "separate-page-table") {
+            return Self::try_new_bare();
         }
+        let mut page_table = PageTable::try_new()?;
+        KERNEL_PROCESS.with_memory_set(|kernel| {
+            page_table.share_kernel_mappings(&kernel.page_table);
+        });
+        Ok(Self {
+            page_table,
+            areas: BTreeMap::<VARangeOrd, MapArea>::new(),
+        })
+    }
+
+    /// 创建只映射了跳板页的地址空间，页帧不足时返回错误
+    pub(super) fn try_new_bare() -> Result<Self, OutOfMemory> {
+        let mut page_table = PageTable::try_new()?;
+        page_table.map_trampoline()?;
+        Ok(Self {
+            page_table,
+            areas: BTreeMap::<VARangeOrd, MapArea>::new(),
+        })
     }
 
     pub fn push(&mut self, mut map_area: MapArea, data: Option<&[u8]>) {
-        map_area.map(&mut self.page_table);
+        map_area.map(&mut self.page_table).unwrap();
     }
 
-    /// 在地址空间插入一段按帧映射的区域，未检查重叠区域
+    /// 在地址空间插入一段按帧映射的区域，未检查重叠区域。
+    /// 页帧不足时不插入区域，已映射的页会被撤销
     pub fn insert_framed_area(
         &mut self,
         va_range: VARange,
         map_perm: MapPermission,
         data: Option<&[u8]>,
-    ) {
+    ) -> Result<(), OutOfMemory> {
         let mut area = MapArea {
             vpn_range: VARangeOrd(va_range.clone()).vpn_range(),
             data_frames: BTreeMap::new(),
@@ -78,16 +161,100 @@ impl MemorySet {
         };
         // println!("{:#x?} {:?}", va_range, map_perm);
         self.page_table
-            .map(VARangeOrd(va_range.clone()), &mut area, data);
+            .map(VARangeOrd(va_range.clone()), &mut area, data)?;
+        self.areas.insert(VARangeOrd(va_range), area);
+        Ok(())
+    }
+    /// 在地址空间插入一段按帧映射的区域，但不分配页帧，第一次访问时由缺页异常分配并清零
+    pub fn insert_lazy_area(&mut self, va_range: VARange, map_perm: MapPermission) {
+        let area = MapArea {
+            vpn_range: VARangeOrd(va_range.clone()).vpn_range(),
+            data_frames: BTreeMap::new(),
+            map_type: MapType::Framed,
+            map_perm,
+        };
         self.areas.insert(VARangeOrd(va_range), area);
     }
-    // fn map_trampoline(&mut self) {
-    //     self.page_table.map(
-    //         VA::from(TRAMPOLINE).into(),
-    //         PA::from(strampoline as usize).into(),
-    //         PTEFlags::R | PTEFlags::X,
-    //     );
-    // }
+
+    /// 处理 `va` 处的缺页异常，`user` 表示来自用户态。
+    /// 按帧映射的区域在第一次访问时分配清零的页帧；
+    /// 页已映射时说明 TLB 过期或需要设置访问位/脏位，更新页表项后刷新 TLB
+    pub fn handle_page_fault(
+        &mut self,
+        va: VA,
+        access: Access,
+        user: bool,
+    ) -> Result<(), PageFaultError> {
+        // 包含 va 的区域与 [va, va + 1) 相等
+        let area = self
+            .areas
+            .get_mut(&VARangeOrd(va..va + 1))
+            .ok_or(PageFaultError::Unmapped)?;
+        if !area.map_perm.contains(access.required(user)) {
+            return Err(PageFaultError::PermissionDenied);
+        }
+        let vpn = va.floor();
+        if let Some(pte) = self.page_table.find_pte(vpn).filter(|pte| pte.is_valid()) {
+            let mut flags = PTEFlags::A;
+            if access == Access::Write {
+                flags |= PTEFlags::D;
+            }
+            *pte = PTE::new(pte.ppn(), pte.flags() | flags);
+        } else {
+            area.map_zeroed(&mut self.page_table, vpn)?;
+        }
+        MmuImpl::flush_tlb_va(vpn.0 << PAGE_SIZE_BITS);
+        Ok(())
+    }
+
+    /// 将线程的内核栈 `stack` 映射到该地址空间，与内核中的映射使用相同的页帧。
+    /// 使用不同页表时，进入内核后切换页表之前 `__trap` 就在内核栈上保存寄存器；
+    /// 共用页表时内核栈已包含在内核的映射中，不需要再映射
+    pub fn map_kernel_stack(&mut self, stack: VARange) -> Result<(), OutOfMemory> {
+        if !cfg!(feature = "separate-page-table") {
+            return Ok(());
+        }
+        let vpn_range = VARangeOrd(stack).vpn_range();
+        for vpn in vpn_range.clone() {
+            let pte = KERNEL_PROCESS
+                .with_memory_set(|kernel| kernel.page_table.find_pte(vpn).map(|pte| *pte))
+                .filter(|pte| pte.is_valid())
+                .expect("kernel stack is not mapped");
+            if let Err(error) = self.page_table.map_one(vpn, PPN(pte.ppn()), pte.flags()) {
+                for mapped in vpn_range.start..vpn {
+                    self.page_table.unmap(mapped);
+                }
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// 撤销 `map_kernel_stack` 的映射，线程退出时调用，页帧仍属于内核
+    pub fn unmap_kernel_stack(&mut self, stack: VARange) {
+        if !cfg!(feature = "separate-page-table") {
+            return;
+        }
+        for vpn in VARangeOrd(stack).vpn_range() {
+            self.page_table.unmap(vpn);
+            MmuImpl::flush_tlb_va(vpn.0 << PAGE_SIZE_BITS);
+        }
+    }
+
+    /// 将 `data` 复制到虚拟地址 `start` 处，可以跨越多个区域，所在的页须已按帧映射
+    fn write(&mut self, start: VA, data: &[u8]) {
+        let mut va = start;
+        let mut data = data;
+        while !data.is_empty() {
+            let offset = va.page_offset();
+            let len = data.len().min(PAGE_SIZE - offset);
+            let frame = &self.areas[&VARangeOrd(va..va + 1)].data_frames[&va.floor()];
+            VPN::from(frame.ppn).get_array::<u8>()[offset..offset + len]
+                .copy_from_slice(&data[..len]);
+            data = &data[len..];
+            va += len;
+        }
+    }
 
     fn new_kernel() -> Self {
         extern "C" {
@@ -100,7 +267,6 @@ impl MemorySet {
             fn sbss_with_stack();
             fn ebss();
             fn ekernel();
-            // fn strampoline();
 
         }
 
@@ -115,7 +281,7 @@ impl MemorySet {
         //     sbss_with_stack as usize, ebss as usize
         // );
         // println!("m");
-        let mut memory_set = Self::new();
+        let mut memory_set = Self::try_new_bare().unwrap();
         // println!("m");
         memory_set.push(
             MapArea::new(
@@ -127,7 +293,7 @@ impl MemorySet {
             None,
         );
 
-        println!("mapping .rodata section");
+        debug!("mapping .rodata section");
 
         memory_set.push(
             MapArea::new(
@@ -139,7 +305,7 @@ impl MemorySet {
             None,
         );
 
-        println!("mapping .data section");
+        debug!("mapping .data section");
 
         memory_set.push(
             MapArea::new(
@@ -151,7 +317,7 @@ impl MemorySet {
             None,
         );
 
-        println!("mapping .bss section");
+        debug!("mapping .bss section");
 
         memory_set.push(
             MapArea::new(
@@ -163,48 +329,127 @@ impl MemorySet {
             None,
         );
 
-        println!("mapping physical memory");
+        debug!("mapping physical memory");
 
         memory_set.push(
             MapArea::new(
                 (ekernel as usize).into(),
-                MEMORY_END.into(),
+                MachineImpl::memory_end().into(),
                 MapType::Linear,
                 MapPermission::R | MapPermission::W,
             ),
             None,
         );
 
+        debug!("mapping memory-mapped registers");
+
+        for (base, size) in MachineImpl::mmio_regions() {
+            memory_set.push(
+                MapArea::new(
+                    (base + KERNEL_MAP_OFFSET).into(),
+                    (base + size + KERNEL_MAP_OFFSET).into(),
+                    MapType::Linear,
+                    MapPermission::R | MapPermission::W,
+                ),
+                None,
+            );
+        }
+
         memory_set
     }
+    /// 该地址空间的页表令牌
+    pub fn token(&self) -> usize {
+        self.page_table.token()
+    }
+
+    /// 切换到该地址空间的页表
     pub fn activate(&self) {
-        println!("active page_table!");
-        let satp = 8usize << 60 | self.page_table.root.ppn.0;
-        println!("active page_table!");
         unsafe {
-            satp::write(satp);
-            println!("{:?}", satp::read().mode());
-            // riscv::asm::ebreak();
-            use riscv::asm::sfence_vma_all;
-            // unsafe {
-            //     sfence_vma_all();
-            // }
-            loop {}
-            asm!("sfence.vma");
-
-            // unsafe {
-            //     unsafe {
-            //         unsafe {
-            //             sfence_vma_all();
-            //         }
-            //     };
-            // }
-        }
-        println!("asasa");
-        // println!("active page_table!");
-    }
-
-    // fn from_elf(elf_data: &[u8]) -> (Self, usize, usize);
+            self.page_table.activate();
+        }
+    }
+
+    /// 由 ELF 可执行文件创建用户地址空间，返回地址空间、入口地址与用户栈顶。
+    /// 各段按帧映射并复制内容，超出文件大小的部分（bss）清零；
+    /// 两个段共用的页单独作为一个区域，权限取两段的并集。
+    /// 用户栈位于 `USER_STACK_TOP` 之下，第一次访问时分配。
+    /// 地址空间由 `try_new` 创建，共用页表时已包含内核的映射
+    pub fn from_elf(elf_data: &[u8]) -> Result<(Self, usize, usize), ElfError> {
+        let elf = ElfFile::parse(elf_data)?;
+        let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+        // 先检查所有段，避免映射到一半时出错
+        let mut segments: Vec<Segment> = Vec::new();
+        for segment in elf.segments() {
+            let segment = segment?;
+            if segment.mem_size == 0 {
+                continue;
+            }
+            // 栈之下留一页保护页
+            if segment.vaddr + segment.mem_size > stack_bottom - PAGE_SIZE {
+                return Err(ElfError::InvalidSegment);
+            }
+            segments.push(segment);
+        }
+        if segments.is_empty() {
+            return Err(ElfError::NoLoadableSegment);
+        }
+        segments.sort_unstable_by_key(|segment| segment.vaddr);
+        if segments
+            .windows(2)
+            .any(|pair| pair[0].vaddr + pair[0].mem_size > pair[1].vaddr)
+        {
+            return Err(ElfError::OverlappingSegments);
+        }
+        let entry = elf.entry();
+        if !segments.iter().any(|segment| {
+            segment.flags & PF_X != 0
+                && (segment.vaddr..segment.vaddr + segment.mem_size).contains(&entry)
+        }) {
+            return Err(ElfError::InvalidEntry);
+        }
+
+        // 段已按地址排序且互不重叠，相邻的段最多共用一页
+        let mut ranges: Vec<(VPNRange, MapPermission)> = Vec::new();
+        for segment in &segments {
+            let perm = MapPermission::from_elf_flags(segment.flags);
+            let mut vpn_range =
+                VA(segment.vaddr).floor()..VA(segment.vaddr + segment.mem_size).ceil();
+            if let Some((last_range, last_perm)) = ranges.last_mut() {
+                if last_range.end > vpn_range.start {
+                    let shared = vpn_range.start;
+                    if last_range.start == shared {
+                        *last_perm |= perm;
+                    } else {
+                        let shared_perm = *last_perm | perm;
+                        last_range.end = shared;
+                        ranges.push((shared..shared + 1, shared_perm));
+                    }
+                    vpn_range.start = shared + 1;
+                }
+            }
+            if vpn_range.start < vpn_range.end {
+                ranges.push((vpn_range, perm));
+            }
+        }
+
+        let mut memory_set = Self::try_new().map_err(|_| ElfError::OutOfMemory)?;
+        for (vpn_range, perm) in ranges {
+            let va_range =
+                VA(vpn_range.start.0 << PAGE_SIZE_BITS)..VA(vpn_range.end.0 << PAGE_SIZE_BITS);
+            // 空的数据表示清零，超出文件大小的部分不需要再处理
+            memory_set
+                .insert_framed_area(va_range, perm, Some(&[]))
+                .map_err(|_| ElfError::OutOfMemory)?;
+        }
+        for segment in &segments {
+            memory_set.write(VA(segment.vaddr), segment.data);
+        }
+        memory_set.insert_lazy_area(
+            VA(stack_bottom)..VA(USER_STACK_TOP),
+            MapPermission::R | MapPermission::W | MapPermission::U,
+        );
+        Ok((memory_set, entry, USER_STACK_TOP))
+    }
 }
 
 impl MapArea {
@@ -219,26 +464,62 @@ impl MapArea {
         }
     }
 
-    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VPN) {
+    /// 映射一页，按帧映射时分配页帧，页帧不足时返回错误
+    pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VPN) -> Result<(), OutOfMemory> {
         let ppn: PPN;
         match self.map_type {
             MapType::Linear => {
-                ppn = PPN(vpn.0);
+                ppn = vpn.into();
             }
             MapType::Framed => {
-                let frame = frame_alloc().unwrap();
+                let frame = frame_alloc().ok_or(OutOfMemory)?;
                 ppn = frame.ppn;
                 self.data_frames.insert(vpn, frame);
             }
         }
         let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
-        page_table.map_one(vpn, ppn, pte_flags);
+        page_table.map_one(vpn, ppn, pte_flags).map_err(|error| {
+            self.data_frames.remove(&vpn);
+            error
+        })
+    }
+
+    /// 撤销一页的映射，按帧映射时释放页帧
+    pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VPN) {
+        page_table.unmap(vpn);
+        self.data_frames.remove(&vpn);
     }
 
-    pub fn map(&mut self, page_table: &mut PageTable) {
+    /// 为按帧映射区域中的 `vpn` 分配一个清零的页帧并映射
+    fn map_zeroed(&mut self, page_table: &mut PageTable, vpn: VPN) -> Result<(), PageFaultError> {
+        if let MapType::Linear = self.map_type {
+            return Err(PageFaultError::Unmapped);
+        }
+        let frame = frame_alloc().ok_or(PageFaultError::OutOfMemory)?;
+        VPN::from(frame.ppn).get_array::<usize>().fill(0);
+        // 页表项的访问位/脏位由软件维护时，缺页处理已经完成了这次访问的检查
+        page_table
+            .map_one(
+                vpn,
+                frame.ppn,
+                self.map_perm.to_pte() | PTEFlags::A | PTEFlags::D,
+            )
+            .map_err(|_| PageFaultError::OutOfMemory)?;
+        self.data_frames.insert(vpn, frame);
+        Ok(())
+    }
+
+    /// 映射区域中的所有页，页帧不足时撤销已映射的页并返回错误
+    pub fn map(&mut self, page_table: &mut PageTable) -> Result<(), OutOfMemory> {
         for vpn in self.vpn_range.clone() {
-            self.map_one(page_table, vpn);
+            if let Err(error) = self.map_one(page_table, vpn) {
+                for mapped in self.vpn_range.start..vpn {
+                    self.unmap_one(page_table, mapped);
+                }
+                return Err(error);
+            }
         }
+        Ok(())
     }
 }
 use alloc::sync::Arc;
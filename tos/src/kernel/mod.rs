@@ -1,7 +1,21 @@
+pub mod clock;
+pub mod drivers;
+pub mod irq;
 pub mod mm;
 pub mod process;
 pub mod sync;
+pub mod timer;
 pub fn init_kernel() {
     mm::init_mm();
     process::init_process();
+    clock::init();
+    irq::init();
+    drivers::init();
+    crate::console::init();
+}
+
+/// 从核初始化，须在主核的 `init_kernel` 完成之后调用
+pub fn init_kernel_secondary() {
+    process::init_process_secondary();
+    irq::init_hart();
 }
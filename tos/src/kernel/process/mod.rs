@@ -6,8 +6,13 @@ use crate::kernel::process::process::KERNEL_PROCESS;
 pub fn init_process() {
     //创建内核进程
 
-    KERNEL_PROCESS.inner.memory_set.activate();
+    KERNEL_PROCESS.with_memory_set(|memory_set| memory_set.activate());
 
     // let mut kernle_process = process::Process::new_kernel();
     // kernle_process.inner.memory_set.activate();
 }
+
+/// 从核切换到内核进程的页表
+pub fn init_process_secondary() {
+    KERNEL_PROCESS.with_memory_set(|memory_set| memory_set.activate());
+}
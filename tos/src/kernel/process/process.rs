@@ -1,4 +1,7 @@
-use crate::kernel::mm::address::VARangeOrd;
+use crate::arch::interface::{Cpu, Interrupt, TrapFrame};
+use crate::arch::{CpuImpl, InterruptImpl};
+use crate::kernel::mm::address::{VARange, VARangeOrd};
+use crate::kernel::mm::frame_allocator::OutOfMemory;
 use crate::kernel::mm::page_table::kernel_page_table;
 use crate::kernel::mm::space::{MapArea, MemorySet};
 use alloc::{
@@ -9,6 +12,7 @@ use alloc::{
     vec,
     vec::Vec,
 };
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::*;
 use spin::Mutex;
 lazy_static! {
@@ -18,44 +22,124 @@ lazy_static! {
         // println!("init kernel process");
         Arc::new(Process {
             pid: 0,
-            inner: ProcessInner {
+            memory_set: Mutex::new(MemorySet {
+                page_table: kernel_page_table(),
+                areas:BTreeMap::<VARangeOrd, MapArea>::new(),
+            }),
+            memory_set_owner: AtomicUsize::new(NO_OWNER),
+            inner: Mutex::new(ProcessInner {
                 // cwd: String::from("/"),
-                memory_set: MemorySet {
-                    page_table: kernel_page_table(),
-                    areas:BTreeMap::<VARangeOrd, MapArea>::new(),
-
-                },
+                pending_signals: 0,
+                exit_code: None,
 
                 // fd_table: vec![Some(STDIN.clone()), Some(STDOUT.clone())],
                 // parent: Weak::new(),
                 // child: Vec::new(),
                 // child_exited: Vec::new(),
                 // wake_callbacks: Vec::new(),
-            },
+            }),
         })
     };
 }
 
 pub type Pid = usize;
+
+/// 非法内存访问
+pub const SIGSEGV: usize = 11;
 pub fn new_test() -> BTreeMap<VARangeOrd, MapArea> {
     let area = BTreeMap::<VARangeOrd, MapArea>::new();
 
-    println!("enter new_test");
+    trace!("enter new_test");
     return area;
 }
 
+/// `memory_set_owner` 中表示没有核持有地址空间的锁
+const NO_OWNER: usize = usize::MAX;
+
+/// 下一个分配的进程号，0 为内核进程
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
 pub struct Process {
     pub pid: Pid,
-    /// 可变的部分。如果要更高的细粒度，去掉 ProcessInner 的 Mutex，给里面的
-    /// memory_set 等等分别加上
-    pub inner: ProcessInner,
+    /// 进程中的线程公用页表 / 内存映射，缺页异常中也会获取，只能通过 `with_memory_set` 访问
+    memory_set: Mutex<MemorySet>,
+    /// 持有 `memory_set` 锁的核，用于发现持有锁时访问用户内存
+    memory_set_owner: AtomicUsize,
+    /// 其余可变的部分
+    pub inner: Mutex<ProcessInner>,
+}
+
+impl Process {
+    /// 以 `memory_set` 为地址空间创建用户进程
+    pub fn new(memory_set: MemorySet) -> Arc<Self> {
+        Arc::new(Self {
+            pid: NEXT_PID.fetch_add(1, Ordering::Relaxed),
+            memory_set: Mutex::new(memory_set),
+            memory_set_owner: AtomicUsize::new(NO_OWNER),
+            inner: Mutex::new(ProcessInner {
+                pending_signals: 0,
+                exit_code: None,
+            }),
+        })
+    }
+
+    /// 为进程中的线程构建进入用户态的初始 `TrapFrame`，`kernel_stack` 为该线程的内核栈。
+    /// 使用不同页表时将内核栈映射到进程的地址空间，`__restore` 根据 `TrapFrame` 中的页表令牌切换页表
+    pub fn init_user_context(
+        &self,
+        trap_frame: &mut impl TrapFrame,
+        kernel_stack: VARange,
+        entry_point: usize,
+        user_stack_top: usize,
+        arguments: Option<&[usize]>,
+    ) -> Result<(), OutOfMemory> {
+        let token = self.with_memory_set(|memory_set| {
+            memory_set.map_kernel_stack(kernel_stack)?;
+            Ok(memory_set.token())
+        })?;
+        trap_frame.init(user_stack_top, entry_point, arguments, true);
+        trap_frame.set_token(token);
+        Ok(())
+    }
+
+    /// 在关中断的情况下访问地址空间。
+    /// 持有期间不能访问用户内存：按需映射的页（如用户栈）会触发缺页异常，而缺页处理需要同一把锁
+    pub fn with_memory_set<T>(&self, f: impl FnOnce(&mut MemorySet) -> T) -> T {
+        unsafe {
+            let enabled = InterruptImpl::disable();
+            let mut memory_set = self.memory_set.lock();
+            self.memory_set_owner.store(CpuImpl::id(), Ordering::Relaxed);
+            let result = f(&mut memory_set);
+            self.memory_set_owner.store(NO_OWNER, Ordering::Relaxed);
+            drop(memory_set);
+            InterruptImpl::restore(enabled);
+            result
+        }
+    }
+
+    /// 当前核是否正持有 `memory_set` 的锁
+    pub fn memory_set_held_by_current(&self) -> bool {
+        self.memory_set_owner.load(Ordering::Relaxed) == CpuImpl::id()
+    }
+
+    /// 向进程发送信号，记录在 `pending_signals` 中
+    pub fn send_signal(&self, signal: usize) {
+        self.inner.lock().pending_signals |= 1 << signal;
+    }
+
+    /// 记录进程的退出码
+    pub fn exit(&self, exit_code: i32) {
+        self.inner.lock().exit_code = Some(exit_code);
+    }
 }
 
 pub struct ProcessInner {
     /// 当前工作目录
     // pub cwd: String,
-    /// 进程中的线程公用页表 / 内存映射
-    pub memory_set: MemorySet,
+    /// 待处理的信号，第 i 位对应信号 i
+    pub pending_signals: u64,
+    /// 退出码，仍在运行时为 None，被信号结束时为 128 + 信号
+    pub exit_code: Option<i32>,
     // 文件描述符
     // pub fd_table: Vec<Option<Arc<FileDescriptor>>>,
     // 父进程
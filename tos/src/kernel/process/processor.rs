@@ -0,0 +1,33 @@
+//! 各核当前运行的进程
+use super::process::{Process, KERNEL_PROCESS};
+use crate::arch::interface::Cpu;
+use crate::arch::CpuImpl;
+use crate::kernel::timer;
+use alloc::sync::Arc;
+
+const NONE: Option<Arc<Process>> = None;
+/// 各核当前的进程，未设置时为内核进程
+static mut CURRENT_PROCESS: [Option<Arc<Process>>; CpuImpl::CPU_NUM] = [NONE; CpuImpl::CPU_NUM];
+
+/// 当前核正在运行的进程
+pub fn current_process() -> Arc<Process> {
+    unsafe { CURRENT_PROCESS[CpuImpl::id()].clone() }.unwrap_or_else(|| KERNEL_PROCESS.clone())
+}
+
+/// 切换到 `process`，并切换到它的地址空间
+pub fn set_current_process(process: Arc<Process>) {
+    process.with_memory_set(|memory_set| memory_set.activate());
+    unsafe { CURRENT_PROCESS[CpuImpl::id()] = Some(process) };
+}
+
+/// 以 `exit_code` 结束当前核上的进程，切换回内核进程的地址空间。
+/// 还没有调度器，本核此后只在空闲中处理中断，不会再返回用户态
+pub fn exit_current(exit_code: i32) -> ! {
+    let process = current_process();
+    process.exit(exit_code);
+    set_current_process(KERNEL_PROCESS.clone());
+    drop(process);
+    loop {
+        timer::idle();
+    }
+}
@@ -0,0 +1,111 @@
+//! 内核定时器
+//!
+//! 每个核一个按到期时间排序的定时器队列，时间以 `time` 寄存器的计数表示。
+//! 时钟中断时执行到期的回调，并将 SBI 定时器设置为周期时钟与最近的定时器中较早的一个。
+//! 回调在完整保存现场的中断上下文中执行（快速路径通过 S 态软件中断转入），此时中断关闭，应尽快返回
+use crate::arch::config::CPU_NUM;
+use crate::arch::interface::{Cpu, Interrupt};
+use crate::arch::timer::{
+    duration_to_time, program_next_event, read_time, restart_tick, stop_tick,
+};
+use crate::arch::{CpuImpl, InterruptImpl};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+
+pub type TimerCallback = Box<dyn FnOnce() + Send>;
+
+/// 定时器的标识，用于取消
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerId {
+    cpu_id: usize,
+    deadline: u64,
+    seq: usize,
+}
+
+/// 以 (到期时间, 序号) 排序，到期时间相同时先添加的先执行
+type TimerQueue = BTreeMap<(u64, usize), TimerCallback>;
+
+const EMPTY_QUEUE: Mutex<TimerQueue> = Mutex::new(BTreeMap::new());
+/// 各核的定时器队列，不需要堆即可使用，时钟中断在建立堆之前就已开启
+static TIMERS: [Mutex<TimerQueue>; CPU_NUM] = [EMPTY_QUEUE; CPU_NUM];
+static NEXT_SEQ: AtomicUsize = AtomicUsize::new(0);
+
+/// 在关中断的情况下访问 `cpu_id` 号核的队列，避免与本核的时钟中断争用锁
+fn with_queue<T>(cpu_id: usize, f: impl FnOnce(&mut TimerQueue) -> T) -> T {
+    unsafe {
+        let enabled = InterruptImpl::disable();
+        let result = f(&mut TIMERS[cpu_id].lock());
+        InterruptImpl::restore(enabled);
+        result
+    }
+}
+
+/// 在当前核添加一个在 `deadline` 时执行的定时器
+pub fn add_timer(deadline: u64, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    let cpu_id = CpuImpl::id();
+    let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+    with_queue(cpu_id, |queue| {
+        queue.insert((deadline, seq), Box::new(callback));
+        // 新的定时器最早到期时，重新设置 SBI 定时器
+        if queue.keys().next() == Some(&(deadline, seq)) {
+            program_next_event(Some(deadline));
+        }
+    });
+    TimerId {
+        cpu_id,
+        deadline,
+        seq,
+    }
+}
+
+/// 添加一个在 `timeout` 之后执行的定时器
+pub fn add_timer_after(timeout: Duration, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    add_timer(read_time() + duration_to_time(timeout), callback)
+}
+
+/// 取消定时器，返回它是否尚未执行
+pub fn cancel_timer(id: TimerId) -> bool {
+    // 被取消的定时器可能仍会触发一次时钟中断，此时没有到期的回调，只会重新设置 SBI 定时器
+    with_queue(id.cpu_id, |queue| {
+        queue.remove(&(id.deadline, id.seq)).is_some()
+    })
+}
+
+/// 当前核最近的定时器到期时间
+pub fn next_deadline() -> Option<u64> {
+    with_queue(CpuImpl::id(), |queue| {
+        queue.keys().next().map(|&(deadline, _)| deadline)
+    })
+}
+
+/// 执行当前核中到期的定时器，由时钟中断调用
+pub fn expire(now: u64) {
+    let cpu_id = CpuImpl::id();
+    loop {
+        // 执行回调时不持有锁，回调中可以添加或取消定时器
+        let callback = with_queue(cpu_id, |queue| match queue.keys().next() {
+            Some(&key) if key.0 <= now => queue.remove(&key),
+            _ => None,
+        });
+        match callback {
+            Some(callback) => callback(),
+            None => break,
+        }
+    }
+}
+
+/// 没有可运行的线程时调用。停止周期时钟，只为最近的定时器设置 SBI 定时器，
+/// 然后在 wfi 中等待。醒来后补上期间的时钟计数，开启中断处理唤醒本核的中断
+pub fn idle() {
+    unsafe {
+        InterruptImpl::disable();
+        // 关中断时 wfi 仍会被 sie 中开启的中断唤醒，不会错过设置定时器之后的中断
+        stop_tick(next_deadline());
+        CpuImpl::wait_for_interrupt();
+        restart_tick();
+        InterruptImpl::enable();
+    }
+}
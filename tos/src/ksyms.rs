@@ -0,0 +1,92 @@
+//! 内核符号表，将地址转换为 `函数名+偏移`
+//!
+//! 符号表由 Makefile 从第一次链接得到的 ELF 中提取，第二次链接时嵌入到 `.ksyms` 段。
+//! 每行为 `16 位十六进制地址 符号名`，按地址升序排列
+use crate::arch::config::TRAMPOLINE;
+use core::fmt;
+
+/// 地址的十六进制位数
+const ADDR_DIGITS: usize = 16;
+
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; include_bytes!(env!("KSYMS_PATH")).len()] =
+    *include_bytes!(env!("KSYMS_PATH"));
+
+/// 通过链接脚本中的符号获取符号表，代码中不出现表的大小，两次链接得到的代码段完全相同
+fn table() -> &'static [u8] {
+    extern "C" {
+        fn eksyms();
+    }
+    let start = KSYMS.as_ptr() as usize;
+    unsafe { core::slice::from_raw_parts(start as *const u8, eksyms as usize - start) }
+}
+
+/// `pos` 所在行的 (地址, 符号名)
+fn line_at(table: &'static [u8], pos: usize) -> Option<(usize, &'static str)> {
+    let start = table[..pos]
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |i| i + 1);
+    let line = &table[start..];
+    let line = &line[..line.iter().position(|&b| b == b'\n').unwrap_or(line.len())];
+    let addr = core::str::from_utf8(line.get(..ADDR_DIGITS)?).ok()?;
+    let name = core::str::from_utf8(line.get(ADDR_DIGITS + 1..)?).ok()?;
+    Some((usize::from_str_radix(addr, 16).ok()?, name))
+}
+
+/// 去掉 Rust 符号末尾的哈希，如 `::h0123456789abcdef`
+fn strip_hash(name: &str) -> &str {
+    match name.rsplit_once("::h") {
+        Some((path, hash)) if hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            path
+        }
+        _ => name,
+    }
+}
+
+/// 查找 `addr` 所在的函数，返回 (函数名, 偏移)，不在代码段中或没有符号表时返回 None
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    extern "C" {
+        fn stext();
+        fn etext();
+        fn strampoline();
+    }
+    // 跳板页中的代码按链接地址查找
+    let addr = if addr >= TRAMPOLINE {
+        addr - TRAMPOLINE + strampoline as usize
+    } else {
+        addr
+    };
+    if addr < stext as usize || addr >= etext as usize {
+        return None;
+    }
+    let table = table();
+    // 每个字节所在行的地址随位置单调不减，二分找到最后一个地址不大于 addr 的位置
+    let (mut lo, mut hi) = (0, table.len());
+    while lo < hi {
+        let mid = (lo + hi) / 2;
+        match line_at(table, mid) {
+            Some((sym_addr, _)) if sym_addr <= addr => lo = mid + 1,
+            _ => hi = mid,
+        }
+    }
+    if lo == 0 {
+        return None;
+    }
+    let (sym_addr, name) = line_at(table, lo - 1)?;
+    Some((strip_hash(name), addr - sym_addr))
+}
+
+/// 以 `地址 <函数名+偏移>` 的形式打印地址
+pub struct Symbol(pub usize);
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#x}", self.0)?;
+        if let Some((name, offset)) = lookup(self.0) {
+            write!(f, " <{}+{:#x}>", name, offset)?;
+        }
+        Ok(())
+    }
+}
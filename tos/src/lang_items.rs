@@ -1,17 +1,40 @@
-use core::panic::PanicInfo;
+use crate::arch::backtrace::backtrace;
+use crate::arch::cpu::get_cpu_id;
 use crate::arch::sbi::shutdown;
-#[panic_handler]
+use crate::arch::trap::current_trap_frame;
+use crate::console::fallback_to_sbi;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// 已经有核在处理 panic，避免回溯时再次 panic 导致无限递归
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // 串口的发送锁可能被持有，改用 SBI 输出
+    fallback_to_sbi();
     if let Some(location) = info.location() {
         println!(
-            "Panicked at {}:{} {}",
+            "[hart {}] Panicked at {}:{} {}",
+            get_cpu_id(),
             location.file(),
             location.line(),
             info.message().unwrap()
         );
     } else {
-        println!("Panicked: {}", info.message().unwrap());
+        println!(
+            "[hart {}] Panicked: {}",
+            get_cpu_id(),
+            info.message().unwrap()
+        );
+    }
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        println!("panicked while panicking, skip backtrace");
+        shutdown()
+    }
+    if let Some(trap_frame) = current_trap_frame() {
+        println!("trap frame:\n{}", trap_frame);
     }
+    backtrace();
     shutdown()
 }
@@ -2,14 +2,19 @@
 #![no_main]
 #![feature(llvm_asm)]
 #![feature(asm)]
+#![feature(asm_const)]
 #![feature(global_asm)]
 #![feature(panic_info_message)]
 #![feature(alloc_error_handler)]
 #![feature(step_trait)]
+#![feature(const_btree_new)]
 extern crate alloc;
 #[macro_use]
 pub mod console;
+#[macro_use]
+pub mod logger;
 pub mod arch;
 mod init;
 pub mod kernel;
+pub mod ksyms;
 mod lang_items;
@@ -0,0 +1,191 @@
+//! 分级的内核日志
+//!
+//! 编译时通过 `error`/`warn`/`info`/`debug`/`trace` 特性选择保留的最低级别，
+//! 运行时通过启动参数 `loglevel=<级别>` 进一步过滤。
+//! 同时作为 `log` crate 的后端，依赖库中的 `log::info!` 等也会输出到这里
+use crate::arch::interface::Interrupt;
+use crate::arch::InterruptImpl;
+use crate::arch::{config::BOOT_CPU_ID, cpu::get_cpu_id, timer::get_ticks};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn from_usize(level: usize) -> Option<Self> {
+        Some(match level {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            5 => Level::Trace,
+            _ => return None,
+        })
+    }
+
+    /// 解析启动参数中的级别，可以是名称或 1~5 的数字
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => return Self::from_usize(s.parse().ok()?),
+        })
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// 各级别对应的 ANSI 颜色
+pub fn level2color(level: Level) -> u8 {
+    match level {
+        Level::Error => 31, // 红
+        Level::Warn => 93,  // 亮黄
+        Level::Info => 34,  // 蓝
+        Level::Debug => 32, // 绿
+        Level::Trace => 90, // 灰
+    }
+}
+
+/// 运行时的最低级别，默认输出编译时保留的所有级别
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Trace as usize);
+
+/// 保证多个核输出的日志不会交错
+static LOG_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn max_level() -> Level {
+    Level::from_usize(MAX_LEVEL.load(Ordering::Relaxed)).unwrap()
+}
+
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(match level {
+        Level::Error => log::LevelFilter::Error,
+        Level::Warn => log::LevelFilter::Warn,
+        Level::Info => log::LevelFilter::Info,
+        Level::Debug => log::LevelFilter::Debug,
+        Level::Trace => log::LevelFilter::Trace,
+    });
+}
+
+/// 由各级别的宏调用，输出 `[级别 核号 时钟中断数] 内容`
+pub fn log(level: Level, args: fmt::Arguments) {
+    if level > max_level() {
+        return;
+    }
+    // 关中断，中断处理中也会输出日志，避免与本核争用锁
+    unsafe {
+        let enabled = InterruptImpl::disable();
+        let guard = LOG_LOCK.lock();
+        println!(
+            "[\x1b[{}m{}\x1b[0m {} {:>6}] {}",
+            level2color(level),
+            level.as_str(),
+            get_cpu_id(),
+            get_ticks(),
+            args
+        );
+        drop(guard);
+        InterruptImpl::restore(enabled);
+    }
+}
+
+struct Logger;
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() as usize <= MAX_LEVEL.load(Ordering::Relaxed)
+    }
+
+    fn log(&self, record: &log::Record) {
+        // log::Level 与 Level 的取值相同
+        let level = Level::from_usize(record.level() as usize).unwrap();
+        log(level, format_args!("{}: {}", record.target(), record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// 从启动参数中读取 `loglevel`，并注册 `log` crate 的后端，由主核调用一次
+pub fn init(bootargs: &str) {
+    debug_assert_eq!(get_cpu_id(), BOOT_CPU_ID);
+    let level = bootargs
+        .split_whitespace()
+        .filter_map(|arg| arg.strip_prefix("loglevel="))
+        .last()
+        .and_then(|level| match Level::parse(level) {
+            Some(level) => Some(level),
+            None => {
+                println!("invalid loglevel \"{}\", ignored", level);
+                None
+            }
+        })
+        .unwrap_or(Level::Trace);
+    log::set_logger(&LOGGER).ok();
+    set_max_level(level);
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ({
+        if cfg!(any(feature = "trace", feature = "debug", feature = "info", feature = "warn", feature = "error")) {
+            $crate::logger::log($crate::logger::Level::Error, format_args!($($arg)*));
+        }
+    })
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ({
+        if cfg!(any(feature = "trace", feature = "debug", feature = "info", feature = "warn")) {
+            $crate::logger::log($crate::logger::Level::Warn, format_args!($($arg)*));
+        }
+    })
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ({
+        if cfg!(any(feature = "trace", feature = "debug", feature = "info")) {
+            $crate::logger::log($crate::logger::Level::Info, format_args!($($arg)*));
+        }
+    })
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ({
+        if cfg!(any(feature = "trace", feature = "debug")) {
+            $crate::logger::log($crate::logger::Level::Debug, format_args!($($arg)*));
+        }
+    })
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ({
+        if cfg!(feature = "trace") {
+            $crate::logger::log($crate::logger::Level::Trace, format_args!($($arg)*));
+        }
+    })
+}
@@ -3,46 +3,88 @@
 #![feature(global_asm)]
 #![feature(asm)]
 #![feature(alloc_error_handler)]
-global_asm!(include_str!("boot/entry.asm"));
-use tos::arch::sbi::shutdown;
-use tos::println;
+#![feature(asm_const)]
+global_asm!(
+    include_str!("boot/entry.asm"),
+    BOOT_STACK_SIZE = const BOOT_STACK_SIZE,
+    CPU_NUM = const CPU_NUM,
+    BOOT_CPU_ID = const BOOT_CPU_ID,
+);
+use core::sync::atomic::{AtomicBool, Ordering};
+use tos::arch::config::{BOOT_CPU_ID, BOOT_STACK_SIZE, CPU_NUM, KERNEL_MAP_OFFSET};
+use tos::arch::cpu::boot_secondary_cpus;
+use tos::arch::sbi::{hart_start, shutdown};
+use tos::{debug, info};
 use tos::{self, console::print};
 extern crate alloc;
-use tos::arch::trap::TrapImpl;
-use tos::arch::trap_context::Trap;
+use tos::arch::interface::Trap;
+use tos::arch::TrapImpl;
 #[macro_use]
 extern crate bitflags;
 use riscv::register::{satp, sstatus};
 pub use tos::kernel::{mm, process};
+
+/// 主核完成 `init_kernel` 之后置为 true，在此之前从核一直等待。
+/// 从核可能在主核清零 .bss 之前读取它，因此放在 .data 中
+#[link_section = ".data"]
+static KERNEL_READY: AtomicBool = AtomicBool::new(false);
+
+/// 清零 .bss，启动栈位于 sbss 之前，不能清零
 fn clear_bss() {
     extern "C" {
-        fn sbss_with_stack();
+        fn sbss();
         fn ebss();
     }
 
     unsafe {
-        let mut cur = sbss_with_stack as *mut usize;
+        let mut cur = sbss as *mut usize;
         let end = ebss as *mut usize;
         while cur < end {
             core::ptr::write_volatile(cur, core::mem::zeroed());
             cur = cur.offset(1);
         }
-
-        // 测试后面的内存是否能访问
-        cur = (crate::tos::arch::config::MEMORY_END as *mut usize).offset(-1);
-        *cur = 0x1234_5678;
-        assert_eq!(*cur, 0x1234_5678);
     }
 }
 
+/// 允许内核读写用户态内存
+unsafe fn allow_user_memory_access() {
+    // k210 遵循 1.9.1 版特权级规范，该位为 PUM，含义与 SUM 相反
+    #[cfg(feature = "k210")]
+    sstatus::clear_sum();
+    #[cfg(not(feature = "k210"))]
+    sstatus::set_sum();
+}
+
 #[no_mangle]
-pub fn rust_main() -> ! {
+pub fn rust_main(hartid: usize, dtb: usize) -> ! {
     use riscv::asm::ebreak;
 
+    if hartid != BOOT_CPU_ID {
+        // SBI 选择了其他核启动，唤醒 BOOT_CPU_ID 作为主核，本核作为从核等待
+        extern "C" {
+            fn _start();
+        }
+        let ret = hart_start(BOOT_CPU_ID, _start as usize - KERNEL_MAP_OFFSET, dtb);
+        if !ret.is_ok() {
+            // 没有其他核在运行内核，清零 .bss 后 panic 处理才能正常使用其中的变量
+            clear_bss();
+            panic!(
+                "booted on hart {}, failed to start boot hart {}: {:?}",
+                hartid,
+                BOOT_CPU_ID,
+                ret.into_result().unwrap_err()
+            );
+        }
+        rust_main_secondary(hartid);
+    }
+    clear_bss();
+    tos::arch::machine::init(dtb);
+    tos::logger::init(tos::arch::machine::machine().bootargs);
+    tos::arch::sbi::init();
+
     unsafe {
         // 允许内核读写用户态内存
-        riscv::register::sstatus::set_sum();
-        println!("{:?}", sstatus::read().spp());
+        allow_user_memory_access();
     }
     // println!("{:#x}", 0x00000 << 10 | 0xCF);
     // println!("{:#x}", 0x40000 << 10 | 0xCF);
@@ -66,17 +108,16 @@ pub fn rust_main() -> ! {
         fn boot_stack();
         fn boot_stack_top();
     }
-    clear_bss();
-    println!("Hello, world!");
-    println!(".text [{:#x}, {:#x})", stext as usize, etext as usize);
-    println!(".rodata [{:#x}, {:#x})", srodata as usize, erodata as usize);
-    println!(".data [{:#x}, {:#x})", sdata as usize, edata as usize);
-    println!(
+    info!("Hello, world! boot hart {}", hartid);
+    debug!(".text [{:#x}, {:#x})", stext as usize, etext as usize);
+    debug!(".rodata [{:#x}, {:#x})", srodata as usize, erodata as usize);
+    debug!(".data [{:#x}, {:#x})", sdata as usize, edata as usize);
+    debug!(
         "boot_stack [{:#x}, {:#x})",
         boot_stack as usize, boot_stack_top as usize
     );
-    println!(".bss [{:#x}, {:#x})", sbss as usize, ebss as usize);
-    println!(".bssstack [{:#x})", sbss_with_stack as usize);
+    debug!(".bss [{:#x}, {:#x})", sbss as usize, ebss as usize);
+    debug!(".bssstack [{:#x})", sbss_with_stack as usize);
     TrapImpl::init();
     // // tos::arch::timer::init();
     // use riscv::asm::ebreak;
@@ -96,9 +137,13 @@ pub fn rust_main() -> ! {
     // let mut a = String::new();
     // a.push('c');
 
+    boot_secondary_cpus(hartid);
+
     tos::kernel::init_kernel();
+    KERNEL_READY.store(true, Ordering::Release);
 
     // tos::kernel::mm::frame_allocator::frame_allocator_test();
+    tos::kernel::mm::elf::elf_loader_test();
     // panic!("end of rust_main");
 
     // unsafe {
@@ -107,6 +152,25 @@ pub fn rust_main() -> ! {
 
     // use riscv::register::satp;
     // println!("{:#?}", satp::read().ppn());
-    loop {}
+    loop {
+        tos::kernel::timer::idle();
+    }
     // shutdown();
 }
+
+#[no_mangle]
+pub fn rust_main_secondary(hartid: usize) -> ! {
+    // 等待主核建立好内核地址空间
+    while !KERNEL_READY.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+    unsafe {
+        allow_user_memory_access();
+    }
+    tos::kernel::init_kernel_secondary();
+    TrapImpl::init();
+    info!("hart {} started", hartid);
+    loop {
+        tos::kernel::timer::idle();
+    }
+}